@@ -4,9 +4,21 @@ use std::{
     io::{Result, Write},
 };
 
-const NET_DEV_FEATURES: &[&str] = &["e1000", "ixgbe", "virtio-net"];
-const BLOCK_DEV_FEATURES: &[&str] = &["ramdisk", "bcm2835-sdhci", "virtio-blk"];
-const DISPLAY_DEV_FEATURES: &[&str] = &["virtio-gpu"];
+/// Driver features of each device category, which is also the name of its
+/// feature. Without the dynamic device model, the first enabled driver of a
+/// category is used.
+const DRIVER_FEATURES: &[(&str, &[&str])] = &[
+    ("net", &["e1000", "ixgbe", "virtio-net"]),
+    ("block", &["ramdisk", "bcm2835-sdhci", "virtio-blk"]),
+    ("display", &["virtio-gpu"]),
+];
+
+/// Default capacity of each device category in the static device model.
+const DEFAULT_MAX_DEVICES: usize = 4;
+/// Default number of IRQs that can have handlers.
+const DEFAULT_MAX_IRQS: usize = 256;
+/// Default number of DMA handles bound to one device each.
+const DEFAULT_DMA_DEVICES: usize = 8;
 
 fn make_cfg_values(str_list: &[&str]) -> String {
     str_list
@@ -24,6 +36,38 @@ fn has_feature(feature: &str) -> bool {
     .is_ok()
 }
 
+/// Passes the positive integer in the environment variable `name` to the
+/// crate, or `default` if it's not set, and returns it.
+fn set_env_usize(name: &str, default: usize) -> usize {
+    let value = match std::env::var(name) {
+        Ok(s) => s
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .unwrap_or_else(|| panic!("{name} must be a positive integer")),
+        Err(_) => default,
+    };
+    println!("cargo:rustc-env={name}={value}");
+    println!("cargo:rerun-if-env-changed={name}");
+    value
+}
+
+/// Generates the macro that gives `with_dma_index!` of `dma/device.rs` one
+/// arm for each of the `count` DMA handles bound to one device.
+fn gen_dma_offsets(count: usize) {
+    let offsets: String = (0..count).map(|i| format!(" {i}")).collect();
+    let code = format!(
+        "/// Expands `with_dma_index!` with the offsets of the handles bound by
+/// `DmaDevice::bind`.
+macro_rules! with_dma_offsets {{
+    ($($args:tt)*) => {{ $crate::dma::with_dma_index!(@ $($args)*;{offsets}) }};
+}}
+"
+    );
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    std::fs::write(format!("{out_dir}/dma_devices.rs"), code).unwrap();
+}
+
 fn enable_cfg(key: &str, value: &str) {
     println!("cargo:rustc-cfg={key}=\"{value}\"");
 }
@@ -48,28 +92,42 @@ img_end:"#,
 }
 
 fn main() {
+    // Without a bus, e.g. for unit tests on the host, only global drivers
+    // are probed.
     if has_feature("bus-mmio") {
         enable_cfg("bus", "mmio");
-    } else {
+    } else if has_feature("bus-pci") {
         enable_cfg("bus", "pci");
     }
 
+    // Capacity of each device category in the static device model, can be
+    // overridden by the `AXDRIVER_MAX_DEVICES` environment variable.
+    set_env_usize("AXDRIVER_MAX_DEVICES", DEFAULT_MAX_DEVICES);
+    // Number of IRQs that can have handlers, can be overridden by the
+    // `AXDRIVER_MAX_IRQS` environment variable.
+    set_env_usize("AXDRIVER_MAX_IRQS", DEFAULT_MAX_IRQS);
+    // Number of DMA handles bound to one device each, can be overridden by
+    // the `AXDRIVER_DMA_DEVICES` environment variable.
+    let dma_devices = set_env_usize("AXDRIVER_DMA_DEVICES", DEFAULT_DMA_DEVICES);
+    gen_dma_offsets(dma_devices);
+
     #[cfg(feature = "img")]
     // 将测例镜像放置在ram-disk中
     new_fs_img().unwrap();
 
-    // Generate cfgs like `net_dev="virtio-net"`. if `dyn` is not enabled, only one device is
-    // selected for each device category. If no device is selected, `dummy` is selected.
-    let is_dyn = has_feature("dyn");
-    for (dev_kind, feat_list) in [
-        ("net", NET_DEV_FEATURES),
-        ("block", BLOCK_DEV_FEATURES),
-        ("display", DISPLAY_DEV_FEATURES),
-    ] {
+    // Generate cfgs like `net_dev="virtio-net"`. if `<kind>-dyn` is not enabled, only one device
+    // is selected for the device category. If no device is selected, `dummy` is selected.
+    // Dynamic categories also get a cfg like `net_dyn`.
+    for &(dev_kind, feat_list) in DRIVER_FEATURES {
         if !has_feature(dev_kind) {
             continue;
         }
 
+        let is_dyn = has_feature(&format!("{dev_kind}-dyn"));
+        if is_dyn {
+            println!("cargo:rustc-cfg={dev_kind}_dyn");
+        }
+
         let mut selected = false;
         for feat in feat_list {
             if has_feature(feat) {
@@ -89,16 +147,11 @@ fn main() {
         "cargo::rustc-check-cfg=cfg(bus, values({}))",
         make_cfg_values(&["pci", "mmio"])
     );
-    println!(
-        "cargo::rustc-check-cfg=cfg(net_dev, values({}, \"dummy\"))",
-        make_cfg_values(NET_DEV_FEATURES)
-    );
-    println!(
-        "cargo::rustc-check-cfg=cfg(block_dev, values({}, \"dummy\"))",
-        make_cfg_values(BLOCK_DEV_FEATURES)
-    );
-    println!(
-        "cargo::rustc-check-cfg=cfg(display_dev, values({}, \"dummy\"))",
-        make_cfg_values(DISPLAY_DEV_FEATURES)
-    );
+    println!("cargo::rustc-check-cfg=cfg(net_dyn, block_dyn, display_dyn)");
+    for &(dev_kind, feat_list) in DRIVER_FEATURES {
+        println!(
+            "cargo::rustc-check-cfg=cfg({dev_kind}_dev, values({}, \"dummy\"))",
+            make_cfg_values(feat_list)
+        );
+    }
 }
@@ -0,0 +1,113 @@
+//! An in-memory block device for unit tests, which records the requests it
+//! gets.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::Mutex;
+
+use super::{check_request, BlockRequestOps};
+use crate::prelude::*;
+
+/// A request to a [`MemDisk`], in blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Request {
+    Read { block_id: u64, count: u64 },
+    Write { block_id: u64, count: u64 },
+    Flush,
+}
+
+pub(crate) struct MemState {
+    pub data: Vec<u8>,
+    pub requests: Vec<Request>,
+}
+
+/// A block device in memory. Clones share the same blocks and requests, so
+/// a test can keep one to look at what another one was asked.
+#[derive(Clone)]
+pub(crate) struct MemDisk {
+    block_size: usize,
+    num_blocks: u64,
+    state: Arc<Mutex<MemState>>,
+}
+
+impl MemDisk {
+    pub fn new(block_size: usize, num_blocks: u64) -> Self {
+        Self {
+            block_size,
+            num_blocks,
+            state: Arc::new(Mutex::new(MemState {
+                data: vec![0; block_size * num_blocks as usize],
+                requests: Vec::new(),
+            })),
+        }
+    }
+
+    /// Puts `bytes` at byte `offset` of the disk, without a request.
+    pub fn set(&self, offset: usize, bytes: &[u8]) {
+        self.state.lock().data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Returns `len` bytes at byte `offset` of the disk, without a request.
+    pub fn get(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.state.lock().data[offset..offset + len].to_vec()
+    }
+
+    /// Returns the requests since the last call.
+    pub fn take_requests(&self) -> Vec<Request> {
+        core::mem::take(&mut self.state.lock().requests)
+    }
+}
+
+impl BaseDriverOps for MemDisk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        "mem"
+    }
+}
+
+impl BlockDriverOps for MemDisk {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let count = check_request(self, block_id, buf.len())?;
+        let mut state = self.state.lock();
+        let start = block_id as usize * self.block_size;
+        buf.copy_from_slice(&state.data[start..start + buf.len()]);
+        state.requests.push(Request::Read { block_id, count });
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let count = check_request(self, block_id, buf.len())?;
+        let mut state = self.state.lock();
+        let start = block_id as usize * self.block_size;
+        state.data[start..start + buf.len()].copy_from_slice(buf);
+        state.requests.push(Request::Write { block_id, count });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.state.lock().requests.push(Request::Flush);
+        Ok(())
+    }
+}
+
+/// Any number of blocks is one request.
+impl BlockRequestOps for MemDisk {
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)
+    }
+}
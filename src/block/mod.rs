@@ -0,0 +1,211 @@
+//! Requests of many blocks at once.
+//!
+//! [`BlockDriverOps::read_block`] and [`BlockDriverOps::write_block`] move
+//! one buffer per call, and not all drivers accept more than one block in
+//! it. [`BlockRequestOps`] adds requests over a contiguous range of blocks,
+//! from one buffer or from a scatter-gather list of buffers. Drivers that
+//! can move a range of blocks at once implement the range requests
+//! natively, e.g. virtio-blk sends one request for the whole range. The
+//! default methods fall back to one block at a time.
+//!
+//! Only some requests are native so far: the ranges of the RAM disk and of
+//! virtio-blk. Scatter-gather lists of virtio-blk are one request per
+//! buffer, and the SDHCI driver moves one block per command, so these are
+//! no faster than a loop over the blocks.
+
+use crate::prelude::*;
+
+#[cfg(all(test, any(feature = "partition", feature = "block-cache")))]
+pub(crate) mod mock;
+
+/// A block device with only [`BlockDriverOps`], whose [`BlockRequestOps`]
+/// are the default methods, one block at a time.
+///
+/// [`AxDeviceEnum::from_block`](crate::AxDeviceEnum::from_block) wraps the
+/// block devices of the dynamic model in it.
+pub struct BlockDriverAdapter<D>(pub D);
+
+impl<D: BlockDriverOps> BaseDriverOps for BlockDriverAdapter<D> {
+    fn device_type(&self) -> DeviceType {
+        self.0.device_type()
+    }
+
+    fn device_name(&self) -> &str {
+        self.0.device_name()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for BlockDriverAdapter<D> {
+    fn num_blocks(&self) -> u64 {
+        self.0.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.0.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.0.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.0.write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.0.flush()
+    }
+}
+
+impl<D: BlockDriverOps> BlockRequestOps for BlockDriverAdapter<D> {}
+
+/// Checks a request of `len` bytes at `block_id` on `dev`, returns the
+/// number of blocks.
+pub(crate) fn check_request<D: BlockDriverOps + ?Sized>(
+    dev: &D,
+    block_id: u64,
+    len: usize,
+) -> DevResult<u64> {
+    let block_size = dev.block_size();
+    if block_size == 0 || len % block_size != 0 {
+        return Err(DevError::InvalidParam);
+    }
+    let count = (len / block_size) as u64;
+    match block_id.checked_add(count) {
+        Some(end) if end <= dev.num_blocks() => Ok(count),
+        _ => Err(DevError::InvalidParam),
+    }
+}
+
+/// Multi-block and scatter-gather requests of a block device.
+///
+/// All buffers must be whole blocks, and the range of blocks must be on the
+/// device. The blocks of a scatter-gather list are contiguous on the device:
+/// the first buffer starts at `block_id`, the next one after it, and so on.
+///
+/// Drivers of the dynamic model that implement it construct their devices
+/// with [`AxDeviceEnum::from_block_requests`](crate::AxDeviceEnum::from_block_requests).
+pub trait BlockRequestOps: BlockDriverOps {
+    /// Reads the blocks starting at `block_id` into `buf`.
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        check_request(self, block_id, buf.len())?;
+        let block_size = self.block_size();
+        for (i, block) in buf.chunks_exact_mut(block_size).enumerate() {
+            self.read_block(block_id + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the blocks starting at `block_id`.
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        check_request(self, block_id, buf.len())?;
+        let block_size = self.block_size();
+        for (i, block) in buf.chunks_exact(block_size).enumerate() {
+            self.write_block(block_id + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks starting at `block_id` into `bufs`, one after
+    /// another.
+    fn read_blocks_sg(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        check_request(self, block_id, len)?;
+        let mut block_id = block_id;
+        for buf in bufs {
+            self.read_blocks(block_id, buf)?;
+            block_id += (buf.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes `bufs`, one after another, to the blocks starting at
+    /// `block_id`.
+    fn write_blocks_sg(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        check_request(self, block_id, len)?;
+        let mut block_id = block_id;
+        for buf in bufs {
+            self.write_blocks(block_id, buf)?;
+            block_id += (buf.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(block_dev = "ramdisk")] {
+        use driver_block::ramdisk::RamDisk;
+
+        /// The RAM disk copies any number of blocks at once.
+        impl BlockRequestOps for RamDisk {
+            fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                self.read_block(block_id, buf)
+            }
+
+            fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                self.write_block(block_id, buf)
+            }
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(block_dev = "bcm2835-sdhci")] {
+        use driver_block::bcm2835sdhci::SDHCIDriver;
+
+        /// Size of the blocks of SD cards.
+        const SDHCI_BLOCK_SIZE: usize = 512;
+
+        /// A block for the SDHCI controller, which moves whole words.
+        #[repr(C, align(4))]
+        struct SdhciBlock([u8; SDHCI_BLOCK_SIZE]);
+
+        fn is_word_aligned(buf: &[u8]) -> bool {
+            buf.as_ptr() as usize % core::mem::align_of::<u32>() == 0
+        }
+
+        /// The SDHCI driver moves one block per command and only into
+        /// word-aligned buffers, so the blocks go one by one, through a
+        /// bounce buffer if they aren't aligned.
+        impl BlockRequestOps for SDHCIDriver {
+            fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                if self.block_size() != SDHCI_BLOCK_SIZE {
+                    return Err(DevError::Unsupported);
+                }
+                let mut bounce = SdhciBlock([0; SDHCI_BLOCK_SIZE]);
+                for (i, block) in buf.chunks_exact_mut(SDHCI_BLOCK_SIZE).enumerate() {
+                    let block_id = block_id + i as u64;
+                    if is_word_aligned(block) {
+                        self.read_block(block_id, block)?;
+                    } else {
+                        self.read_block(block_id, &mut bounce.0)?;
+                        block.copy_from_slice(&bounce.0);
+                    }
+                }
+                Ok(())
+            }
+
+            fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                if self.block_size() != SDHCI_BLOCK_SIZE {
+                    return Err(DevError::Unsupported);
+                }
+                let mut bounce = SdhciBlock([0; SDHCI_BLOCK_SIZE]);
+                for (i, block) in buf.chunks_exact(SDHCI_BLOCK_SIZE).enumerate() {
+                    let block_id = block_id + i as u64;
+                    if is_word_aligned(block) {
+                        self.write_block(block_id, block)?;
+                    } else {
+                        bounce.0.copy_from_slice(block);
+                        self.write_block(block_id, &bounce.0)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,633 @@
+//! A block cache wrapped around block devices.
+//!
+//! With [`InitArgs::block_cache`](crate::InitArgs::block_cache), each block
+//! device is wrapped after probing in a cache of recently used blocks, so that
+//! repeated reads of metadata don't go to the device. Partitions share the
+//! cache of their disk.
+//!
+//! - **LRU**: the least recently used block is evicted when the cache is full.
+//! - **Write-back**: written blocks stay dirty in the cache, and are written
+//!   to the device when they are evicted or flushed. `flush` is a barrier: all
+//!   blocks written before it are on the device, and the device is flushed,
+//!   before it returns. Blocks written before a flush never reach the device
+//!   after blocks written after it. The blocks still dirty when the device
+//!   is dropped are written back then, errors are only logged.
+//! - **Read-ahead**: when a read continues the previous one, the blocks after
+//!   it are read along in the same request.
+//!
+//! Hit and miss counters are returned by [`AllDevices::block_cache_stats`].
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::{block::check_request, prelude::*, AllDevices, DeviceName};
+
+/// Index of no slot, the end of the LRU list.
+const NIL: usize = usize::MAX;
+
+/// Settings of the block cache, given to [`init_drivers_with`].
+///
+/// [`init_drivers_with`]: crate::init_drivers_with
+#[derive(Debug, Clone, Copy)]
+pub struct BlockCacheConfig {
+    /// Maximum number of blocks cached for each device.
+    pub capacity: usize,
+    /// Number of blocks read ahead of a sequential read, 0 to disable
+    /// read-ahead.
+    pub read_ahead: usize,
+    /// Keep written blocks in the cache until they are evicted or flushed.
+    /// Otherwise they are written through to the device.
+    pub write_back: bool,
+}
+
+impl Default for BlockCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            read_ahead: 32,
+            write_back: true,
+        }
+    }
+}
+
+/// Counters of a block cache, in blocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockCacheStats {
+    /// Blocks read from the cache.
+    pub hits: u64,
+    /// Blocks read from the device.
+    pub misses: u64,
+    /// Blocks read from the device ahead of a sequential read.
+    pub read_ahead: u64,
+    /// Dirty blocks written to the device.
+    pub write_backs: u64,
+    /// Blocks evicted to make room for others.
+    pub evictions: u64,
+}
+
+/// The name of each cached device and its cache.
+///
+/// The caches are kept even if the device is taken out of [`AllDevices`], so
+/// that they can still be flushed at shutdown. They are gone once the device
+/// is dropped.
+pub(crate) type CacheList = Vec<(DeviceName, Weak<Mutex<BlockCache>>)>;
+
+struct Slot {
+    block_id: u64,
+    dirty: bool,
+    /// The previous, more recently used slot.
+    prev: usize,
+    /// The next, less recently used slot.
+    next: usize,
+}
+
+/// A block device with a cache.
+pub(crate) struct BlockCache {
+    inner: AxBlockDevice,
+    config: BlockCacheConfig,
+    block_size: usize,
+    num_blocks: u64,
+    slots: Vec<Slot>,
+    /// The data of each slot, one block after another.
+    data: Vec<u8>,
+    /// The slot of each cached block.
+    index: BTreeMap<u64, usize>,
+    /// The most recently used slot.
+    head: usize,
+    /// The least recently used slot.
+    tail: usize,
+    /// The block after the last read, to detect sequential reads.
+    next_read: u64,
+    stats: BlockCacheStats,
+}
+
+impl BlockCache {
+    fn new(inner: AxBlockDevice, config: BlockCacheConfig) -> Self {
+        Self {
+            block_size: inner.block_size(),
+            num_blocks: inner.num_blocks(),
+            inner,
+            config,
+            slots: Vec::new(),
+            data: Vec::new(),
+            index: BTreeMap::new(),
+            head: NIL,
+            tail: NIL,
+            next_read: 0,
+            stats: BlockCacheStats::default(),
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.slots[slot].prev, self.slots[slot].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.slots[slot].prev = NIL;
+        self.slots[slot].next = self.head;
+        match self.head {
+            NIL => self.tail = slot,
+            head => self.slots[head].prev = slot,
+        }
+        self.head = slot;
+    }
+
+    /// Returns the slot of `block_id` and marks it as the most recently used.
+    fn lookup(&mut self, block_id: u64) -> Option<usize> {
+        let slot = *self.index.get(&block_id)?;
+        self.unlink(slot);
+        self.push_front(slot);
+        Some(slot)
+    }
+
+    fn slot_data(&mut self, slot: usize) -> &mut [u8] {
+        &mut self.data[slot * self.block_size..][..self.block_size]
+    }
+
+    /// Takes a slot for `block_id`, evicting the least recently used block
+    /// if the cache is full.
+    fn alloc_slot(&mut self, block_id: u64) -> DevResult<usize> {
+        let slot = if self.slots.len() < self.config.capacity {
+            self.slots.push(Slot {
+                block_id,
+                dirty: false,
+                prev: NIL,
+                next: NIL,
+            });
+            self.data.resize(self.slots.len() * self.block_size, 0);
+            self.slots.len() - 1
+        } else {
+            let slot = self.tail;
+            if self.slots[slot].dirty {
+                let (victim, bs) = (self.slots[slot].block_id, self.block_size);
+                self.inner
+                    .write_block(victim, &self.data[slot * bs..][..bs])?;
+                self.stats.write_backs += 1;
+            }
+            self.stats.evictions += 1;
+            self.index.remove(&self.slots[slot].block_id);
+            self.unlink(slot);
+            self.slots[slot].block_id = block_id;
+            self.slots[slot].dirty = false;
+            slot
+        };
+        self.index.insert(block_id, slot);
+        self.push_front(slot);
+        Ok(slot)
+    }
+
+    /// Caches the blocks in `buf` read from the device at `block_id`,
+    /// unless they are already cached.
+    fn fill(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        for (i, block) in buf.chunks_exact(self.block_size).enumerate() {
+            let block_id = block_id + i as u64;
+            if !self.index.contains_key(&block_id) {
+                let slot = self.alloc_slot(block_id)?;
+                self.slot_data(slot).copy_from_slice(block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads uncached blocks at `block_id` into `buf`, and the blocks after
+    /// them if `read_ahead` is set.
+    fn read_missing(&mut self, block_id: u64, buf: &mut [u8], read_ahead: bool) -> DevResult {
+        let end = block_id + (buf.len() / self.block_size) as u64;
+        let mut ahead = 0;
+        while read_ahead
+            && ahead < self.config.read_ahead as u64
+            && end + ahead < self.num_blocks
+            && !self.index.contains_key(&(end + ahead))
+        {
+            ahead += 1;
+        }
+        self.stats.misses += buf.len() as u64 / self.block_size as u64;
+        if ahead == 0 {
+            self.inner.read_blocks(block_id, buf)?;
+            return self.fill(block_id, buf);
+        }
+        let mut tmp = vec![0; buf.len() + ahead as usize * self.block_size];
+        self.inner.read_blocks(block_id, &mut tmp)?;
+        buf.copy_from_slice(&tmp[..buf.len()]);
+        self.stats.read_ahead += ahead;
+        self.fill(block_id, &tmp)
+    }
+
+    /// Writes back the dirty blocks, contiguous ones in one request.
+    fn write_back(&mut self) -> DevResult {
+        let dirty: Vec<(u64, usize)> = self
+            .index
+            .iter()
+            .filter(|(_, &slot)| self.slots[slot].dirty)
+            .map(|(&block_id, &slot)| (block_id, slot))
+            .collect();
+        let mut run = Vec::new();
+        let mut i = 0;
+        while i < dirty.len() {
+            let start = dirty[i].0;
+            run.clear();
+            while i < dirty.len() && dirty[i].0 == start + (run.len() / self.block_size) as u64 {
+                let slot = dirty[i].1;
+                run.extend_from_slice(self.slot_data(slot));
+                i += 1;
+            }
+            self.inner.write_blocks(start, &run)?;
+            for &(_, slot) in &dirty[i - run.len() / self.block_size..i] {
+                self.slots[slot].dirty = false;
+            }
+            self.stats.write_backs += (run.len() / self.block_size) as u64;
+        }
+        Ok(())
+    }
+}
+
+impl BaseDriverOps for BlockCache {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+}
+
+impl BlockDriverOps for BlockCache {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let count = check_request(self, block_id, buf.len())?;
+        let sequential = block_id == self.next_read;
+        self.next_read = block_id + count;
+        let bs = self.block_size;
+        let mut i = 0;
+        while i < count {
+            if let Some(slot) = self.lookup(block_id + i) {
+                let data = &self.data[slot * bs..][..bs];
+                buf[i as usize * bs..][..bs].copy_from_slice(data);
+                self.stats.hits += 1;
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < count && !self.index.contains_key(&(block_id + i)) {
+                i += 1;
+            }
+            let run = &mut buf[start as usize * bs..i as usize * bs];
+            // Only read ahead at the end of the request.
+            self.read_missing(block_id + start, run, sequential && i == count)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        check_request(self, block_id, buf.len())?;
+        if !self.config.write_back {
+            self.inner.write_blocks(block_id, buf)?;
+        }
+        for (i, block) in buf.chunks_exact(self.block_size).enumerate() {
+            let block_id = block_id + i as u64;
+            let slot = match self.lookup(block_id) {
+                Some(slot) => slot,
+                None if self.config.write_back => self.alloc_slot(block_id)?,
+                None => continue,
+            };
+            self.slot_data(slot).copy_from_slice(block);
+            self.slots[slot].dirty = self.config.write_back;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.write_back()?;
+        self.inner.flush()
+    }
+}
+
+/// `read_block` and `write_block` take any number of blocks already.
+impl BlockRequestOps for BlockCache {
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)
+    }
+}
+
+impl Drop for BlockCache {
+    /// Writes the dirty blocks back, as nothing can flush them afterwards.
+    fn drop(&mut self) {
+        if !self.slots.iter().any(|slot| slot.dirty) {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            let dirty = self.slots.iter().filter(|slot| slot.dirty).count();
+            error!(
+                "block cache of {}: {} dirty blocks are lost: {:?}",
+                self.inner.device_name(),
+                dirty,
+                e
+            );
+        }
+    }
+}
+
+/// The block device registered for a cached device, sharing the cache with
+/// [`AllDevices`].
+struct CachedDevice {
+    cache: Arc<Mutex<BlockCache>>,
+    /// The name of the device given by its driver.
+    device_name: DeviceName,
+}
+
+impl BaseDriverOps for CachedDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+impl BlockDriverOps for CachedDevice {
+    fn num_blocks(&self) -> u64 {
+        self.cache.lock().num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.cache.lock().block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.cache.lock().read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.cache.lock().write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.cache.lock().flush()
+    }
+}
+
+impl BlockRequestOps for CachedDevice {
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.cache.lock().read_block(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.cache.lock().write_block(block_id, buf)
+    }
+}
+
+impl AllDevices {
+    /// Wraps each block device in a cache.
+    pub(crate) fn add_block_caches(&mut self, config: &BlockCacheConfig) {
+        if config.capacity == 0 {
+            return;
+        }
+        info!(
+            "block cache: {} blocks, read-ahead {}, write-{}",
+            config.capacity,
+            config.read_ahead,
+            if config.write_back { "back" } else { "through" }
+        );
+        for index in 0..self.block.len() {
+            if self.block[index].block_size() == 0 {
+                continue;
+            }
+            let Some((dev, info)) = self.block.take(index) else {
+                break;
+            };
+            let device_name = DeviceName::new(dev.device_name());
+            let cache = Arc::new(Mutex::new(BlockCache::new(dev, *config)));
+            self.block_caches.push((info.name, Arc::downgrade(&cache)));
+            self.block
+                .insert(index, Box::new(CachedDevice { cache, device_name }), info);
+        }
+    }
+
+    /// Flushes the caches of all block devices, including the devices taken
+    /// out of `self`.
+    pub(crate) fn flush_block_caches(&mut self) -> DevResult {
+        self.block_caches
+            .retain(|(_, cache)| cache.strong_count() > 0);
+        for (name, cache) in self.block_caches.iter() {
+            if let Some(cache) = cache.upgrade() {
+                cache.lock().flush().inspect_err(|e| {
+                    warn!("failed to flush the block cache of {}: {:?}", name, e)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the counters of the cache of the block device named `name`.
+    ///
+    /// Partitions share the cache of their disk, so the counters of a disk
+    /// include the accesses to its partitions.
+    pub fn block_cache_stats(&self, name: &str) -> Option<BlockCacheStats> {
+        self.block.position_by_name(name)?;
+        let (_, cache) = self.block_caches.iter().find(|(n, _)| *n == *name)?;
+        Some(cache.upgrade()?.lock().stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::{MemDisk, Request};
+
+    const BS: usize = 512;
+    const NUM_BLOCKS: u64 = 64;
+
+    fn new_cache(capacity: usize, read_ahead: usize) -> (BlockCache, MemDisk) {
+        let disk = MemDisk::new(BS, NUM_BLOCKS);
+        let config = BlockCacheConfig {
+            capacity,
+            read_ahead,
+            write_back: true,
+        };
+        (BlockCache::new(Box::new(disk.clone()), config), disk)
+    }
+
+    fn read(cache: &mut BlockCache, block_id: u64) -> Vec<u8> {
+        let mut buf = vec![0; BS];
+        cache.read_block(block_id, &mut buf).unwrap();
+        buf
+    }
+
+    fn write(cache: &mut BlockCache, block_id: u64, byte: u8) {
+        cache.write_block(block_id, &[byte; BS]).unwrap();
+    }
+
+    const fn read_req(block_id: u64, count: u64) -> Request {
+        Request::Read { block_id, count }
+    }
+
+    const fn write_req(block_id: u64, count: u64) -> Request {
+        Request::Write { block_id, count }
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let (mut cache, disk) = new_cache(2, 0);
+        disk.set(BS, &[1; BS]);
+        read(&mut cache, 0);
+        assert_eq!(read(&mut cache, 1), [1; BS]);
+        // Block 1 is the least recently used after this hit.
+        read(&mut cache, 0);
+        read(&mut cache, 2);
+        read(&mut cache, 0);
+        read(&mut cache, 1);
+        assert_eq!(
+            disk.take_requests(),
+            [
+                read_req(0, 1),
+                read_req(1, 1),
+                read_req(2, 1),
+                read_req(1, 1)
+            ]
+        );
+        let stats = cache.stats;
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 4, 2));
+    }
+
+    #[test]
+    fn eviction_writes_back() {
+        let (mut cache, disk) = new_cache(2, 0);
+        write(&mut cache, 0, 0xa);
+        write(&mut cache, 1, 0xb);
+        assert!(disk.take_requests().is_empty());
+        read(&mut cache, 2);
+        assert_eq!(disk.take_requests(), [read_req(2, 1), write_req(0, 1)]);
+        assert_eq!(disk.get(0, BS), [0xa; BS]);
+        assert_eq!(cache.stats.write_backs, 1);
+        // The clean copy of block 2 is evicted without a write.
+        read(&mut cache, 3);
+        read(&mut cache, 4);
+        assert_eq!(
+            disk.take_requests(),
+            [read_req(3, 1), write_req(1, 1), read_req(4, 1)]
+        );
+    }
+
+    #[test]
+    fn flush_coalesces_dirty_runs() {
+        let (mut cache, disk) = new_cache(16, 0);
+        for block_id in [5, 3, 10, 4, 7, 9] {
+            write(&mut cache, block_id, block_id as u8);
+        }
+        cache.flush().unwrap();
+        assert_eq!(
+            disk.take_requests(),
+            [
+                write_req(3, 3),
+                write_req(7, 1),
+                write_req(9, 2),
+                Request::Flush
+            ]
+        );
+        for block_id in [3, 4, 5, 7, 9, 10] {
+            assert_eq!(disk.get(block_id * BS, BS), [block_id as u8; BS]);
+        }
+        assert_eq!(cache.stats.write_backs, 6);
+        // Nothing is dirty anymore.
+        cache.flush().unwrap();
+        assert_eq!(disk.take_requests(), [Request::Flush]);
+    }
+
+    #[test]
+    fn sequential_reads_read_ahead() {
+        let (mut cache, disk) = new_cache(16, 4);
+        disk.set(3 * BS, &[3; BS]);
+        read(&mut cache, 0);
+        for block_id in 1..5 {
+            let data = read(&mut cache, block_id);
+            assert_eq!(data[0], if block_id == 3 { 3 } else { 0 });
+        }
+        read(&mut cache, 5);
+        assert_eq!(disk.take_requests(), [read_req(0, 5), read_req(5, 5)]);
+        assert_eq!(cache.stats.read_ahead, 8);
+        // Not after the previous read.
+        read(&mut cache, 20);
+        // Not past the end of the disk.
+        read(&mut cache, 62);
+        read(&mut cache, 63);
+        assert_eq!(
+            disk.take_requests(),
+            [read_req(20, 1), read_req(62, 1), read_req(63, 1)]
+        );
+    }
+
+    #[test]
+    fn read_ahead_stops_at_cached_blocks() {
+        let (mut cache, disk) = new_cache(16, 4);
+        read(&mut cache, 3);
+        read(&mut cache, 0);
+        read(&mut cache, 1);
+        assert_eq!(
+            disk.take_requests(),
+            [read_req(3, 1), read_req(0, 1), read_req(1, 2)]
+        );
+    }
+
+    #[test]
+    fn write_through() {
+        let disk = MemDisk::new(BS, NUM_BLOCKS);
+        let config = BlockCacheConfig {
+            capacity: 16,
+            read_ahead: 0,
+            write_back: false,
+        };
+        let mut cache = BlockCache::new(Box::new(disk.clone()), config);
+        read(&mut cache, 1);
+        write(&mut cache, 1, 1);
+        write(&mut cache, 2, 2);
+        assert_eq!(read(&mut cache, 1), [1; BS]);
+        assert_eq!(
+            disk.take_requests(),
+            [read_req(1, 1), write_req(1, 1), write_req(2, 1)]
+        );
+        cache.flush().unwrap();
+        assert_eq!(disk.take_requests(), [Request::Flush]);
+    }
+
+    #[test]
+    fn drop_writes_back() {
+        let (mut cache, disk) = new_cache(16, 0);
+        write(&mut cache, 8, 8);
+        drop(cache);
+        assert_eq!(disk.take_requests(), [write_req(8, 1), Request::Flush]);
+        assert_eq!(disk.get(8 * BS, BS), [8; BS]);
+
+        let (mut cache, disk) = new_cache(16, 0);
+        read(&mut cache, 8);
+        disk.take_requests();
+        drop(cache);
+        assert!(disk.take_requests().is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+//! Helpers for reading devices and interrupts from the flattened device tree.
+
+use axhal::mem::phys_to_virt;
+use fdt::{node::FdtNode, Fdt};
+
+/// Parses the device tree blob at physical address `paddr`.
+pub(crate) fn parse(paddr: usize) -> Option<Fdt<'static>> {
+    let fdt = unsafe { Fdt::from_ptr(phys_to_virt(paddr.into()).as_ptr()) };
+    fdt.inspect_err(|e| warn!("invalid device tree at PA:{:#x}: {:?}", paddr, e))
+        .ok()
+}
+
+/// Returns whether the `status` property of the node is absent or `"okay"`.
+pub(crate) fn node_enabled(node: FdtNode) -> bool {
+    match node.property("status").and_then(|p| p.as_str()) {
+        Some(status) => status == "okay" || status == "ok",
+        None => true,
+    }
+}
+
+fn be32_cells(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+}
+
+fn prop_u32(node: FdtNode, name: &str) -> Option<u32> {
+    be32_cells(node.property(name)?.value).next()
+}
+
+/// Decodes an interrupt specifier of an interrupt controller with
+/// `#interrupt-cells = cells`, returns the interrupt number seen by the
+/// controller.
+fn decode_irq(cells: usize, spec: &mut impl Iterator<Item = u32>) -> Option<usize> {
+    let (irq, used) = if cells == 3 {
+        // ARM GIC: <type number flags>, where type 0 is SPI and 1 is PPI.
+        let (ty, num) = (spec.next()? as usize, spec.next()? as usize);
+        (if ty == 0 { num + 32 } else { num + 16 }, 2)
+    } else {
+        (spec.next()? as usize, 1)
+    };
+    // Skip the remaining cells of this specifier.
+    for _ in used..cells {
+        spec.next();
+    }
+    Some(irq)
+}
+
+/// Decodes the first interrupt specifier in the `interrupts` property of the
+/// node, returns the interrupt number seen by the interrupt controller.
+pub(crate) fn node_irq(fdt: &Fdt, node: FdtNode) -> Option<usize> {
+    let prop = node.property("interrupts")?;
+    let parent = node
+        .interrupt_parent()
+        .or_else(|| fdt.find_node("/")?.interrupt_parent())?;
+    let cells = parent.interrupt_cells().unwrap_or(1);
+    decode_irq(cells, &mut be32_cells(prop.value))
+}
+
+/// Routes a PCI INTx pin through the `interrupt-map` of the PCI host bridge
+/// node, returns the interrupt number seen by the interrupt controller.
+///
+/// `bus`, `device` and `function` identify the function on the root bus, and
+/// `pin` (1 for INTA# to 4 for INTD#) must already be swizzled through any
+/// bridges in between.
+pub(crate) fn pci_intx_irq(fdt: &Fdt, bus: u8, device: u8, function: u8, pin: u8) -> Option<usize> {
+    let host = fdt.all_nodes().find(|node| {
+        node.property("device_type").and_then(|p| p.as_str()) == Some("pci")
+            && node.property("interrupt-map").is_some()
+    })?;
+    let addr_cells = prop_u32(host, "#address-cells").unwrap_or(3) as usize;
+    let intr_cells = prop_u32(host, "#interrupt-cells").unwrap_or(1) as usize;
+    if addr_cells != 3 || intr_cells != 1 {
+        return None;
+    }
+
+    let mask: [u32; 4] = match host.property("interrupt-map-mask") {
+        Some(p) => {
+            let mut cells = be32_cells(p.value);
+            [cells.next()?, cells.next()?, cells.next()?, cells.next()?]
+        }
+        None => [u32::MAX; 4],
+    };
+    let child_hi = ((bus as u32) << 16) | ((device as u32) << 11) | ((function as u32) << 8);
+    let key = [child_hi & mask[0], 0, 0, pin as u32 & mask[3]];
+
+    let mut map = be32_cells(host.property("interrupt-map")?.value);
+    loop {
+        let entry = [map.next()?, map.next()?, map.next()?, map.next()?];
+        let parent = fdt.find_phandle(map.next()?)?;
+        let parent_addr_cells = prop_u32(parent, "#address-cells").unwrap_or(0);
+        for _ in 0..parent_addr_cells {
+            map.next()?;
+        }
+        let parent_cells = parent.interrupt_cells().unwrap_or(1);
+        let irq = decode_irq(parent_cells, &mut map)?;
+        if entry[0] & mask[0] == key[0] && entry[3] & mask[3] == key[3] {
+            return Some(irq);
+        }
+    }
+}
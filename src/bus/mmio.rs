@@ -1,23 +1,105 @@
+use super::devtree;
 #[allow(unused_imports)]
-use crate::{prelude::*, AllDevices};
+use crate::probe::{DeferredProbes, ProbeOutcome, ProbeSite};
+#[allow(unused_imports)]
+use crate::{
+    cmdline, prelude::*, AllDevices, AxDeviceInfo, DeviceIrq, DeviceLocation, InitArgs,
+    ScannedDevice,
+};
+use axhal::mem::phys_to_virt;
+use fdt::Fdt;
+
+/// The magic value `"virt"` at offset 0 of virtio-mmio registers.
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+/// Offset of the device status register of virtio-mmio devices.
+const VIRTIO_MMIO_STATUS: usize = 0x70;
 
 impl AllDevices {
-    pub(crate) fn probe_bus_devices(&mut self) {
-        // TODO: parse device tree
+    pub(crate) fn probe_bus_devices(&mut self, args: &InitArgs, deferred: &mut DeferredProbes) {
+        match args.dtb_paddr.and_then(devtree::parse) {
+            Some(fdt) => self.probe_fdt_devices(&fdt, deferred),
+            None => self.probe_static_devices(deferred),
+        }
+    }
+
+    /// Probes all enabled device tree nodes that have both `compatible` and
+    /// `reg` properties.
+    fn probe_fdt_devices(&mut self, fdt: &Fdt, deferred: &mut DeferredProbes) {
+        for node in fdt.all_nodes() {
+            let Some(compatible) = node.compatible() else {
+                continue;
+            };
+            if !devtree::node_enabled(node) {
+                continue;
+            }
+            let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+                continue;
+            };
+            let (base, size) = (reg.starting_address as usize, reg.size.unwrap_or(0));
+            let irq = devtree::node_irq(fdt, node);
+            let drivers = cmdline::drivers().filter(|driver| {
+                compatible
+                    .all()
+                    .any(|s| driver.mmio_compatible.contains(&s))
+            });
+            let info = AxDeviceInfo {
+                location: DeviceLocation::Mmio { base, size },
+                irq: irq.map(DeviceIrq::Line),
+                ..Default::default()
+            };
+            self.inventory.push(ScannedDevice {
+                compatible: Some(compatible.first()),
+                irq: info.irq,
+                ..ScannedDevice::new(info.location)
+            });
+            let site = ProbeSite::Mmio { base, size };
+            if self.probe_site(drivers, site, info, deferred) == ProbeOutcome::NotFound {
+                debug!("no driver for device tree node {}", node.name);
+            }
+        }
+    }
+
+    /// Probes the fixed MMIO regions in the platform config, used when no
+    /// device tree is supplied.
+    fn probe_static_devices(&mut self, deferred: &mut DeferredProbes) {
         #[cfg(feature = "virtio")]
         for reg in axconfig::VIRTIO_MMIO_REGIONS {
-            for_each_drivers!(type Driver, {
-                if let Some(dev) = Driver::probe_mmio(reg.0, reg.1) {
-                    info!(
-                        "registered a new {:?} device at [PA:{:#x}, PA:{:#x}): {:?}",
-                        dev.device_type(),
-                        reg.0, reg.0 + reg.1,
-                        dev.device_name(),
-                    );
-                    self.add_device(dev);
-                    continue; // skip to the next device
+            let (base, size) = (reg.0, reg.1);
+            let info = AxDeviceInfo {
+                location: DeviceLocation::Mmio { base, size },
+                ..Default::default()
+            };
+            self.inventory.push(ScannedDevice::new(info.location));
+            let site = ProbeSite::Mmio { base, size };
+            let drivers =
+                cmdline::drivers().filter(|driver| driver.mmio_compatible.contains(&"virtio,mmio"));
+            self.probe_site(drivers, site, info, deferred);
+        }
+        let _ = deferred;
+    }
+
+    /// MMIO devices have nothing to quiesce at the bus level.
+    pub(crate) fn quiesce_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    /// MMIO devices have no bus resources to release.
+    pub(crate) fn release_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    /// MMIO devices have no bus-level DMA control.
+    pub(crate) fn suspend_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    pub(crate) fn resume_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    /// Resets virtio-mmio devices.
+    pub(crate) fn shutdown_bus_device(&mut self, location: &DeviceLocation) {
+        if let DeviceLocation::Mmio { base, size } = *location {
+            if size >= VIRTIO_MMIO_STATUS + 4 {
+                let regs = phys_to_virt(base.into()).as_mut_ptr() as *mut u32;
+                unsafe {
+                    if regs.read_volatile() == VIRTIO_MMIO_MAGIC {
+                        regs.add(VIRTIO_MMIO_STATUS / 4).write_volatile(0);
+                    }
                 }
-            });
+            }
         }
     }
 }
@@ -1,4 +1,16 @@
+#[cfg(any(feature = "bus-mmio", feature = "bus-pci"))]
+mod devtree;
+
 #[cfg(bus = "mmio")]
 mod mmio;
+#[cfg(not(any(bus = "mmio", bus = "pci")))]
+mod none;
 #[cfg(bus = "pci")]
 mod pci;
+
+#[cfg(all(bus = "pci", feature = "virtio"))]
+pub(crate) use self::pci::VirtIoCommonCfg;
+#[cfg(bus = "pci")]
+pub(crate) use self::pci::{pci_location, pci_root, PciHost};
+#[cfg(bus = "pci")]
+pub use self::pci::{MsiDomain, PciDeviceId, PciIrq, PCI_ANY_ID};
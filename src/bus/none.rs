@@ -0,0 +1,22 @@
+//! No bus is enabled, devices are only found by
+//! [`DriverProbe::probe_global`](crate::DriverProbe::probe_global).
+//!
+//! This is also how the unit tests build on the host, where the bus modules
+//! can't, as they depend on `axhal`.
+
+use crate::probe::DeferredProbes;
+use crate::{AllDevices, DeviceLocation, InitArgs};
+
+impl AllDevices {
+    pub(crate) fn probe_bus_devices(&mut self, _args: &InitArgs, _deferred: &mut DeferredProbes) {}
+
+    pub(crate) fn quiesce_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    pub(crate) fn release_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    pub(crate) fn suspend_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    pub(crate) fn resume_bus_device(&mut self, _location: &DeviceLocation) {}
+
+    pub(crate) fn shutdown_bus_device(&mut self, _location: &DeviceLocation) {}
+}
@@ -0,0 +1,66 @@
+//! PCI device ID tables matched by the bus before probing drivers.
+
+use driver_pci::DeviceFunctionInfo;
+
+/// A wildcard for the vendor and device fields of [`PciDeviceId`].
+pub const PCI_ANY_ID: u16 = 0xffff;
+
+/// An entry of the PCI ID table of a driver.
+///
+/// The vendor and device fields match anything if they are [`PCI_ANY_ID`].
+/// The class code (`class << 16 | subclass << 8 | prog_if`) matches if the
+/// bits in `class_mask` are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDeviceId {
+    pub vendor: u16,
+    pub device: u16,
+    pub subvendor: u16,
+    pub subdevice: u16,
+    pub class: u32,
+    pub class_mask: u32,
+}
+
+impl PciDeviceId {
+    /// Matches the vendor and device ID, with any subsystem and class.
+    pub const fn new(vendor: u16, device: u16) -> Self {
+        Self {
+            vendor,
+            device,
+            subvendor: PCI_ANY_ID,
+            subdevice: PCI_ANY_ID,
+            class: 0,
+            class_mask: 0,
+        }
+    }
+
+    /// Matches any device of the class code under `class_mask`.
+    pub const fn class(class: u32, class_mask: u32) -> Self {
+        Self::new(PCI_ANY_ID, PCI_ANY_ID).with_class(class, class_mask)
+    }
+
+    /// Also requires the subsystem vendor and subsystem ID.
+    pub const fn with_subsystem(mut self, subvendor: u16, subdevice: u16) -> Self {
+        self.subvendor = subvendor;
+        self.subdevice = subdevice;
+        self
+    }
+
+    /// Also requires the class code under `class_mask`.
+    pub const fn with_class(mut self, class: u32, class_mask: u32) -> Self {
+        self.class = class;
+        self.class_mask = class_mask;
+        self
+    }
+
+    /// Returns whether the function matches this entry, `subsystem` is its
+    /// subsystem vendor and subsystem ID.
+    pub(crate) fn matches(&self, info: &DeviceFunctionInfo, subsystem: (u16, u16)) -> bool {
+        let class = (info.class as u32) << 16 | (info.subclass as u32) << 8 | info.prog_if as u32;
+        let id_matches = |want: u16, got: u16| want == PCI_ANY_ID || want == got;
+        id_matches(self.vendor, info.vendor_id)
+            && id_matches(self.device, info.device_id)
+            && id_matches(self.subvendor, subsystem.0)
+            && id_matches(self.subdevice, subsystem.1)
+            && (class ^ self.class) & self.class_mask == 0
+    }
+}
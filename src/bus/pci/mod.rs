@@ -0,0 +1,900 @@
+mod id;
+mod msi;
+mod power;
+mod virtio;
+
+use super::devtree;
+use crate::probe::{DeferredProbes, ProbeOutcome, ProbeSite};
+use crate::{
+    cmdline, prelude::*, AllDevices, AxDeviceInfo, DeviceIrq, DeviceLocation, DriverEntry,
+    InitArgs, ScanStatus, ScannedDevice,
+};
+use arrayvec::ArrayVec;
+use axhal::mem::phys_to_virt;
+use driver_pci::{
+    BarInfo, Cam, Command, DeviceFunction, DeviceFunctionInfo, HeaderType, MemoryBarType, PciRoot,
+};
+use fdt::Fdt;
+
+pub use self::id::{PciDeviceId, PCI_ANY_ID};
+pub use self::msi::{MsiDomain, PciIrq};
+pub(crate) use self::virtio::VirtIoCommonCfg;
+
+const PCI_BAR_NUM: u8 = 6;
+const PCI_BRIDGE_BAR_NUM: u8 = 2;
+
+/// Granularity of the memory window of a PCI-to-PCI bridge.
+const PCI_BRIDGE_MEM_ALIGN: u64 = 0x10_0000;
+/// Granularity of the I/O window of a PCI-to-PCI bridge.
+const PCI_BRIDGE_IO_ALIGN: u64 = 0x1000;
+
+/// The first I/O port given to PCI devices, ports below it are reserved for
+/// legacy ISA devices.
+const PCI_IO_START: u64 = 0x1000;
+
+/// Offset of the subsystem vendor ID and subsystem ID of a type 0 header.
+const PCI_SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+
+const PCI_STATUS: u16 = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_CAP_PTR: u16 = 0x34;
+
+/// Maximum number of freed ranges remembered by a [`PciWindow`].
+const PCI_WINDOW_MAX_FREED: usize = 16;
+/// Maximum number of BARs whose addresses are tracked for release.
+const PCI_MAX_ASSIGNED_BARS: usize = 64;
+
+// Configuration space registers of a PCI-to-PCI bridge (header type 1).
+const PCI_BRIDGE_BUS_NUMBERS: u16 = 0x18;
+const PCI_BRIDGE_IO_BASE_LIMIT: u16 = 0x1c;
+const PCI_BRIDGE_MEM_BASE_LIMIT: u16 = 0x20;
+const PCI_BRIDGE_PREF_BASE_LIMIT: u16 = 0x24;
+const PCI_BRIDGE_PREF_BASE_UPPER: u16 = 0x28;
+const PCI_BRIDGE_PREF_LIMIT_UPPER: u16 = 0x2c;
+const PCI_BRIDGE_IO_UPPER: u16 = 0x30;
+
+/// Value of the low 4 bits of the prefetchable base register of a bridge that
+/// supports 64-bit addressing.
+const PCI_BRIDGE_PREF_64BIT: u32 = 0x1;
+
+/// Raw access to the PCI configuration space through ECAM.
+#[derive(Clone, Copy)]
+struct PciConfig {
+    base_vaddr: usize,
+}
+
+impl PciConfig {
+    /// The ECAM configuration space of the host bridge.
+    fn ecam() -> Self {
+        Self {
+            base_vaddr: phys_to_virt(axconfig::PCI_ECAM_BASE.into()).as_usize(),
+        }
+    }
+
+    fn reg_ptr<T>(&self, bdf: DeviceFunction, offset: u16) -> *mut T {
+        let addr = self.base_vaddr
+            + ((bdf.bus as usize) << 20)
+            + ((bdf.device as usize) << 15)
+            + ((bdf.function as usize) << 12)
+            + (offset as usize & !(core::mem::size_of::<T>() - 1));
+        addr as *mut T
+    }
+
+    fn read(&self, bdf: DeviceFunction, offset: u16) -> u32 {
+        unsafe { self.reg_ptr::<u32>(bdf, offset).read_volatile() }
+    }
+
+    fn write(&self, bdf: DeviceFunction, offset: u16, value: u32) {
+        unsafe { self.reg_ptr::<u32>(bdf, offset).write_volatile(value) }
+    }
+
+    fn read16(&self, bdf: DeviceFunction, offset: u16) -> u16 {
+        unsafe { self.reg_ptr::<u16>(bdf, offset).read_volatile() }
+    }
+
+    fn write16(&self, bdf: DeviceFunction, offset: u16, value: u16) {
+        unsafe { self.reg_ptr::<u16>(bdf, offset).write_volatile(value) }
+    }
+
+    fn read8(&self, bdf: DeviceFunction, offset: u16) -> u8 {
+        unsafe { self.reg_ptr::<u8>(bdf, offset).read_volatile() }
+    }
+
+    /// Returns the ID and offset of all capabilities in the list.
+    fn capabilities(self, bdf: DeviceFunction) -> impl Iterator<Item = (u8, u16)> {
+        let mut ptr = if self.read16(bdf, PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            self.read8(bdf, PCI_CAP_PTR) & 0xfc
+        } else {
+            0
+        };
+        // Bound the walk in case of a malformed (looping) list.
+        (0..48).map_while(move |_| {
+            if ptr == 0 {
+                return None;
+            }
+            let offset = ptr as u16;
+            ptr = self.read8(bdf, offset + 1) & 0xfc;
+            Some((self.read8(bdf, offset), offset))
+        })
+    }
+}
+
+/// A bump allocator for a PCI address window, also used for the IRQ numbers
+/// of the MSI domain.
+struct PciWindow {
+    end: u64,
+    current: u64,
+    /// Ranges given back by [`free`](Self::free), reused before the space
+    /// after `current`.
+    freed: ArrayVec<PciFreedRange, PCI_WINDOW_MAX_FREED>,
+}
+
+/// A range given back to a [`PciWindow`].
+#[derive(Clone, Copy)]
+struct PciFreedRange {
+    start: u64,
+    end: u64,
+    /// The bridge whose secondary bus the range was allocated for, `None`
+    /// for a root bus. The range is inside the forwarding window of that
+    /// bridge, so it can only be reused for devices on the same bus.
+    bridge: Option<DeviceFunction>,
+}
+
+impl PciWindow {
+    const fn new(base: u64, size: u64) -> Self {
+        Self {
+            end: base + size,
+            current: base,
+            freed: ArrayVec::new_const(),
+        }
+    }
+
+    /// Allocates a naturally aligned range for a device behind `bridge`,
+    /// `size` must be a power of two.
+    fn alloc(&mut self, size: u64, bridge: Option<DeviceFunction>) -> Option<u64> {
+        if !size.is_power_of_two() {
+            return None;
+        }
+        self.alloc_aligned(size, size, bridge)
+    }
+
+    /// Allocates a range whose start is a multiple of `align`, for a device
+    /// behind `bridge`.
+    fn alloc_aligned(
+        &mut self,
+        size: u64,
+        align: u64,
+        bridge: Option<DeviceFunction>,
+    ) -> Option<u64> {
+        if let Some(addr) = self.alloc_freed(size, align, bridge) {
+            return Some(addr);
+        }
+        let ret = self.current.next_multiple_of(align);
+        if ret + size > self.end {
+            return None;
+        }
+        self.current = ret + size;
+        Some(ret)
+    }
+
+    /// Allocates from the ranges freed behind `bridge`, splitting the one
+    /// that fits.
+    fn alloc_freed(
+        &mut self,
+        size: u64,
+        align: u64,
+        bridge: Option<DeviceFunction>,
+    ) -> Option<u64> {
+        let (i, addr) = self.freed.iter().enumerate().find_map(|(i, range)| {
+            let addr = range.start.next_multiple_of(align);
+            (range.bridge == bridge && addr + size <= range.end).then_some((i, addr))
+        })?;
+        let range = self.freed.swap_remove(i);
+        self.add_freed(range.start, addr, bridge);
+        self.add_freed(addr + size, range.end, bridge);
+        Some(addr)
+    }
+
+    /// Gives back a range allocated by [`alloc`](Self::alloc) for a device
+    /// behind `bridge`.
+    ///
+    /// The range is only reused for devices behind the same bridge, as the
+    /// forwarding window of the bridge may already be programmed to cover
+    /// it.
+    fn free(&mut self, addr: u64, size: u64, bridge: Option<DeviceFunction>) {
+        self.add_freed(addr, addr + size, bridge);
+    }
+
+    fn add_freed(&mut self, start: u64, end: u64, bridge: Option<DeviceFunction>) {
+        if start >= end {
+            return;
+        }
+        let range = PciFreedRange { start, end, bridge };
+        if self.freed.try_push(range).is_err() {
+            warn!(
+                "PCI window: too many free ranges, leaking [{:#x}, {:#x})",
+                start, end
+            );
+        }
+    }
+
+    /// Moves the allocation pointer to the next multiple of `align` and
+    /// returns it.
+    fn align(&mut self, align: u64) -> u64 {
+        self.current = self.current.next_multiple_of(align).min(self.end);
+        self.current
+    }
+}
+
+/// Port-mapped register access to an I/O BAR of a PCI function.
+///
+/// On x86, the `in`/`out` instructions are used. On other platforms, the PCI
+/// I/O space is accessed through the memory-mapped I/O window of the host
+/// bridge (the first range in `PCI_RANGES`).
+///
+/// It's only used to reset legacy virtio devices at shutdown. Drivers are not
+/// given I/O BARs: the driver crates only take the base of a memory BAR, and
+/// the e1000 and ixgbe registers are always in the memory BAR0.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PciIoBar {
+    port: u32,
+    size: u32,
+}
+
+impl PciIoBar {
+    /// Creates the accessor for an assigned I/O BAR of the function.
+    ///
+    /// Returns `None` if the BAR is not an I/O BAR, is unassigned, or the
+    /// platform has no way to reach the PCI I/O space.
+    pub fn new(root: &mut PciRoot, bdf: DeviceFunction, bar: u8) -> Option<Self> {
+        match root.bar_info(bdf, bar).ok()? {
+            BarInfo::IO { address, size } if address > 0 && size > 0 => {
+                if cfg!(not(target_arch = "x86_64")) && axconfig::PCI_RANGES.is_empty() {
+                    return None;
+                }
+                Some(Self {
+                    port: address,
+                    size,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the I/O port at `offset`, panics if it's out of the BAR.
+    fn port_at(&self, offset: u32, width: u32) -> u32 {
+        assert!(offset + width <= self.size, "I/O BAR access out of range");
+        self.port + offset
+    }
+
+    /// Writes an 8-bit register at `offset`.
+    pub fn write8(&self, offset: u32, value: u8) {
+        unsafe { port_io::write8(self.port_at(offset, 1), value) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod port_io {
+    use core::arch::asm;
+
+    pub unsafe fn write8(port: u32, value: u8) {
+        asm!("out dx, al", in("dx") port as u16, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod port_io {
+    use axhal::mem::phys_to_virt;
+
+    pub unsafe fn write8(port: u32, value: u8) {
+        let paddr = axconfig::PCI_RANGES[0].0 + port as usize;
+        let ptr = phys_to_virt(paddr.into()).as_mut_ptr();
+        ptr.write_volatile(value)
+    }
+}
+
+/// Which window of the host bridge a BAR is allocated from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PciSpace {
+    Io,
+    Mem32,
+    Mem64,
+}
+
+/// A BAR range allocated during enumeration, released when the function is
+/// unbound.
+struct PciAssignedBar {
+    bdf: DeviceFunction,
+    /// The bridge whose secondary bus the function is on.
+    bridge: Option<DeviceFunction>,
+    space: PciSpace,
+    addr: u64,
+    size: u64,
+}
+
+/// The state of PCI enumeration under one host bridge.
+///
+/// It's kept in [`AllDevices`] after enumeration, so that resources can be
+/// released when a device is unbound.
+pub(crate) struct PciHost {
+    root: PciRoot,
+    config: PciConfig,
+    /// PCI I/O space, in bus addresses.
+    io: Option<PciWindow>,
+    /// PCI 32-bit MMIO space.
+    mem32: Option<PciWindow>,
+    /// PCI 64-bit MMIO space, used for 64-bit prefetchable BARs.
+    mem64: Option<PciWindow>,
+    /// Whether all bridges on the path to the bus being scanned can forward
+    /// the 64-bit prefetchable window.
+    pref64: bool,
+    /// The next bus number to assign to a bridge.
+    next_bus: usize,
+    bus_end: usize,
+    /// The platform MSI domain, if message signaled interrupts are usable.
+    msi: Option<MsiDomain>,
+    /// The IRQs of the MSI domain.
+    msi_irqs: Option<PciWindow>,
+    /// The device tree, used to route INTx pins.
+    fdt: Option<Fdt<'static>>,
+    /// When scanning behind bridges: the bridge on the root bus, and the sum
+    /// of device numbers of the bridges below it, for INTx pin swizzling.
+    intx_swizzle: Option<(DeviceFunction, usize)>,
+    /// The bridge whose secondary bus is being scanned, `None` for a root
+    /// bus.
+    bridge: Option<DeviceFunction>,
+    /// BARs assigned by [`config_device`](Self::config_device).
+    assigned: ArrayVec<PciAssignedBar, PCI_MAX_ASSIGNED_BARS>,
+}
+
+/// Returns the root complex of the ECAM configuration space.
+pub(crate) fn pci_root() -> PciRoot {
+    let base_vaddr = phys_to_virt(axconfig::PCI_ECAM_BASE.into());
+    unsafe { PciRoot::new(base_vaddr.as_mut_ptr(), Cam::Ecam) }
+}
+
+impl PciHost {
+    fn new(args: &InitArgs) -> Self {
+        Self {
+            root: pci_root(),
+            config: PciConfig::ecam(),
+            io: axconfig::PCI_RANGES.get(0).map(|range| {
+                let size = range.1 as u64;
+                PciWindow::new(PCI_IO_START, size.saturating_sub(PCI_IO_START))
+            }),
+            mem32: axconfig::PCI_RANGES
+                .get(1)
+                .map(|range| PciWindow::new(range.0 as u64, range.1 as u64)),
+            mem64: axconfig::PCI_RANGES
+                .get(2)
+                .map(|range| PciWindow::new(range.0 as u64, range.1 as u64)),
+            pref64: true,
+            next_bus: 0,
+            bus_end: axconfig::PCI_BUS_END,
+            msi: args.msi,
+            msi_irqs: args
+                .msi
+                .map(|domain| PciWindow::new(domain.irq_base as u64, domain.irq_count as u64)),
+            fdt: args.dtb_paddr.and_then(devtree::parse),
+            intx_swizzle: None,
+            bridge: None,
+            assigned: ArrayVec::new(),
+        }
+    }
+
+    /// Enumerates all buses. Bus 0 and everything behind its bridges is
+    /// scanned first, the remaining bus numbers are scanned as extra root
+    /// buses.
+    fn scan(&mut self, devs: &mut AllDevices, deferred: &mut DeferredProbes) {
+        while self.next_bus <= self.bus_end {
+            let bus = self.next_bus;
+            self.next_bus += 1;
+            self.scan_bus(devs, deferred, bus as u8);
+        }
+    }
+
+    /// Allocates an address for a memory BAR from the window that matches
+    /// its type. 64-bit prefetchable BARs are placed in the 64-bit window if
+    /// possible, and fall back to the 32-bit window.
+    fn alloc_mem_bar(
+        &mut self,
+        size: u64,
+        width64: bool,
+        prefetchable: bool,
+    ) -> DevResult<(u64, PciSpace)> {
+        let bridge = self.bridge;
+        if width64 && prefetchable && self.pref64 {
+            if let Some(addr) = self.mem64.as_mut().and_then(|w| w.alloc(size, bridge)) {
+                return Ok((addr, PciSpace::Mem64));
+            }
+        }
+        self.mem32
+            .as_mut()
+            .and_then(|w| w.alloc(size, bridge))
+            .map(|addr| (addr, PciSpace::Mem32))
+            .ok_or(DevError::NoMemory)
+    }
+
+    fn window(&mut self, space: PciSpace) -> Option<&mut PciWindow> {
+        match space {
+            PciSpace::Io => self.io.as_mut(),
+            PciSpace::Mem32 => self.mem32.as_mut(),
+            PciSpace::Mem64 => self.mem64.as_mut(),
+        }
+    }
+
+    /// Records a BAR range allocated for `bdf`.
+    fn track_bar(&mut self, bdf: DeviceFunction, space: PciSpace, addr: u64, size: u64) {
+        let bar = PciAssignedBar {
+            bdf,
+            bridge: self.bridge,
+            space,
+            addr,
+            size,
+        };
+        if self.assigned.try_push(bar).is_err() {
+            warn!(
+                "too many PCI BARs, [{:#x}, {:#x}) can't be released",
+                addr,
+                addr + size
+            );
+        }
+    }
+
+    /// Assigns addresses to all unassigned BARs of the function and enables
+    /// it.
+    fn config_device(&mut self, bdf: DeviceFunction, bar_num: u8) -> DevResult {
+        let mut bar = 0;
+        while bar < bar_num {
+            let info = self.root.bar_info(bdf, bar).unwrap();
+            // if the BAR address is not assigned, call the allocator and assign it.
+            match info {
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    address,
+                    size,
+                } if size > 0 && address == 0 => {
+                    let width64 = address_type == MemoryBarType::Width64;
+                    let (new_addr, space) = self
+                        .alloc_mem_bar(size as _, width64, prefetchable)
+                        .inspect_err(|_| warn!("  BAR {}: no space for {:#x} bytes", bar, size))?;
+                    self.track_bar(bdf, space, new_addr, size as _);
+                    if address_type == MemoryBarType::Width32 {
+                        self.root.set_bar_32(bdf, bar, new_addr as _);
+                    } else if width64 {
+                        self.root.set_bar_64(bdf, bar, new_addr);
+                    }
+                }
+                BarInfo::IO { address, size } if size > 0 && address == 0 => {
+                    // Without an I/O window, the BAR is left for the driver to
+                    // ignore, as most devices also provide a memory BAR.
+                    let bridge = self.bridge;
+                    if let Some(io) = self.io.as_mut() {
+                        let new_port = io.alloc(size as _, bridge).ok_or_else(|| {
+                            warn!("  BAR {}: no I/O space for {:#x} bytes", bar, size);
+                            DevError::NoMemory
+                        })?;
+                        self.track_bar(bdf, PciSpace::Io, new_port, size as _);
+                        self.root.set_bar_32(bdf, bar, new_port as _);
+                    }
+                }
+                _ => {}
+            }
+
+            // read the BAR info again after assignment.
+            let info = self.root.bar_info(bdf, bar).unwrap();
+            match info {
+                BarInfo::IO { address, size } => {
+                    if address > 0 && size > 0 {
+                        debug!("  BAR {}: IO  [{:#x}, {:#x})", bar, address, address + size);
+                    }
+                }
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    address,
+                    size,
+                } => {
+                    if address > 0 && size > 0 {
+                        debug!(
+                            "  BAR {}: MEM [{:#x}, {:#x}){}{}",
+                            bar,
+                            address,
+                            address + size as u64,
+                            if address_type == MemoryBarType::Width64 {
+                                " 64bit"
+                            } else {
+                                ""
+                            },
+                            if prefetchable { " pref" } else { "" },
+                        );
+                    }
+                }
+            }
+
+            bar += 1;
+            if info.takes_two_entries() {
+                bar += 1;
+            }
+        }
+
+        // Enable the device.
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(
+            bdf,
+            cmd | Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
+        );
+        Ok(())
+    }
+
+    /// Returns the assigned BARs of the function, with their indices.
+    fn bars(&mut self, bdf: DeviceFunction, bar_num: u8) -> ArrayVec<(u8, BarInfo), 6> {
+        let mut bars = ArrayVec::new();
+        let mut bar = 0;
+        while bar < bar_num {
+            let Ok(info) = self.root.bar_info(bdf, bar) else {
+                break;
+            };
+            let next = bar + if info.takes_two_entries() { 2 } else { 1 };
+            let assigned = match info {
+                BarInfo::Memory { address, size, .. } => address != 0 && size > 0,
+                BarInfo::IO { address, size } => address != 0 && size > 0,
+            };
+            if assigned {
+                bars.push((bar, info));
+            }
+            bar = next;
+        }
+        bars
+    }
+
+    fn scan_bus(&mut self, devs: &mut AllDevices, deferred: &mut DeferredProbes, bus: u8) {
+        for (bdf, dev_info) in self.root.enumerate_bus(bus) {
+            debug!("PCI {}: {}", bdf, dev_info);
+            match dev_info.header_type {
+                HeaderType::Standard => self.probe_function(devs, deferred, bdf, &dev_info),
+                HeaderType::PciPciBridge => self.scan_bridge(devs, deferred, bdf, &dev_info),
+                _ => {}
+            }
+        }
+    }
+
+    fn probe_function(
+        &mut self,
+        devs: &mut AllDevices,
+        deferred: &mut DeferredProbes,
+        bdf: DeviceFunction,
+        dev_info: &DeviceFunctionInfo,
+    ) {
+        let location = pci_location(bdf);
+        let mut scanned = ScannedDevice {
+            pci_info: Some(dev_info.clone()),
+            ..ScannedDevice::new(location)
+        };
+        if let Err(e) = self.config_device(bdf, PCI_BAR_NUM) {
+            warn!(
+                "failed to enable PCI device at {}({}): {:?}",
+                bdf, dev_info, e
+            );
+            self.release_bars(bdf);
+            devs.inventory.push(scanned);
+            devs.inventory.set_error(&location, None, e);
+            return;
+        }
+
+        let subsystem = self.config.read(bdf, PCI_SUBSYSTEM_VENDOR_ID);
+        let subsystem = (subsystem as u16, (subsystem >> 16) as u16);
+        let matches = |driver: &&DriverEntry| {
+            driver
+                .pci_ids
+                .iter()
+                .any(|id| id.matches(dev_info, subsystem))
+        };
+        // MSI is only set up if every driver that may get the function
+        // programs the vectors, otherwise it would get no interrupts.
+        let msi = cmdline::drivers().filter(matches).all(|driver| driver.msi);
+        let irq = self.setup_irq(bdf, msi);
+        let info = AxDeviceInfo {
+            location,
+            irq: self.device_irq(bdf, irq),
+            ..Default::default()
+        };
+        scanned.bars = self.bars(bdf, PCI_BAR_NUM);
+        scanned.irq = info.irq;
+        devs.inventory.push(scanned);
+        #[cfg(feature = "iommu")]
+        crate::dma::iommu::attach(&location);
+        let site = ProbeSite::Pci {
+            bdf,
+            dev_info: dev_info.clone(),
+            irq,
+        };
+        let drivers = cmdline::drivers().filter(matches);
+        // A deferred function keeps its interrupts until it's probed again.
+        if devs.probe_site(drivers, site, info, deferred) == ProbeOutcome::NotFound {
+            // No driver claims the function, give its interrupts back.
+            self.release_irq(bdf, irq);
+            #[cfg(feature = "iommu")]
+            crate::dma::iommu::detach(&location);
+        }
+    }
+
+    /// Converts the interrupt set up for a function into the IRQ it raises.
+    fn device_irq(&self, bdf: DeviceFunction, irq: PciIrq) -> Option<DeviceIrq> {
+        match irq {
+            PciIrq::None => None,
+            PciIrq::Msi { base, count } | PciIrq::MsiX { base, count } => {
+                Some(DeviceIrq::Msi { base, count })
+            }
+            PciIrq::Intx { pin, line } => self
+                .route_intx(bdf, pin)
+                .or(line.map(|line| line as usize))
+                .map(DeviceIrq::Line),
+        }
+    }
+
+    /// Routes an INTx pin to the interrupt controller through the
+    /// `interrupt-map` of the host bridge, swizzling it across bridges.
+    fn route_intx(&self, bdf: DeviceFunction, pin: u8) -> Option<usize> {
+        let fdt = self.fdt.as_ref()?;
+        let (root_dev, pin) = match self.intx_swizzle {
+            Some((top, sum)) => {
+                let swizzled = (pin as usize - 1 + sum + bdf.device as usize) % 4 + 1;
+                (top, swizzled as u8)
+            }
+            None => (bdf, pin),
+        };
+        devtree::pci_intx_irq(fdt, root_dev.bus, root_dev.device, root_dev.function, pin)
+    }
+
+    /// Assigns bus numbers to a PCI-to-PCI bridge, scans its secondary bus
+    /// recursively, and programs its forwarding windows to cover everything
+    /// allocated behind it.
+    fn scan_bridge(
+        &mut self,
+        devs: &mut AllDevices,
+        deferred: &mut DeferredProbes,
+        bdf: DeviceFunction,
+        dev_info: &DeviceFunctionInfo,
+    ) {
+        let location = pci_location(bdf);
+        devs.inventory.push(ScannedDevice {
+            pci_info: Some(dev_info.clone()),
+            status: ScanStatus::Bridge,
+            ..ScannedDevice::new(location)
+        });
+        if self.next_bus > self.bus_end {
+            warn!("no bus number left for PCI bridge at {}({})", bdf, dev_info);
+            devs.inventory
+                .set_error(&location, None, DevError::NoMemory);
+            return;
+        }
+        if let Err(e) = self.config_device(bdf, PCI_BRIDGE_BAR_NUM) {
+            warn!(
+                "failed to enable PCI bridge at {}({}): {:?}",
+                bdf, dev_info, e
+            );
+            devs.inventory.set_error(&location, None, e);
+            return;
+        }
+
+        // Claim all the remaining bus numbers while scanning, so that
+        // configuration cycles reach any bridges further down.
+        let secondary = self.next_bus;
+        self.next_bus += 1;
+        self.set_bus_numbers(bdf, secondary, self.bus_end);
+
+        // The prefetchable window is only usable if the bridge supports 64-bit
+        // addressing in it.
+        let parent_pref64 = self.pref64;
+        let pref_cap = self.config.read(bdf, PCI_BRIDGE_PREF_BASE_LIMIT) & 0xf;
+        self.pref64 = parent_pref64 && pref_cap == PCI_BRIDGE_PREF_64BIT;
+
+        let io_start = self.io.as_mut().map(|w| w.align(PCI_BRIDGE_IO_ALIGN));
+        let mem_start = self.mem32.as_mut().map(|w| w.align(PCI_BRIDGE_MEM_ALIGN));
+        let parent_swizzle = self.intx_swizzle;
+        self.intx_swizzle = Some(match parent_swizzle {
+            Some((top, sum)) => (top, sum + bdf.device as usize),
+            None => (bdf, 0),
+        });
+        let pref_start = self.mem64.as_mut().map(|w| w.align(PCI_BRIDGE_MEM_ALIGN));
+        let parent_bridge = self.bridge.replace(bdf);
+        self.scan_bus(devs, deferred, secondary as u8);
+        self.bridge = parent_bridge;
+        let io_end = self.io.as_mut().map(|w| w.align(PCI_BRIDGE_IO_ALIGN));
+        let mem_end = self.mem32.as_mut().map(|w| w.align(PCI_BRIDGE_MEM_ALIGN));
+        let pref_end = self.mem64.as_mut().map(|w| w.align(PCI_BRIDGE_MEM_ALIGN));
+        self.intx_swizzle = parent_swizzle;
+
+        let pref_range = if self.pref64 {
+            pref_start.zip(pref_end)
+        } else {
+            None
+        };
+        self.pref64 = parent_pref64;
+
+        let subordinate = self.next_bus - 1;
+        self.set_bus_numbers(bdf, secondary, subordinate);
+        self.set_mem_window(bdf, mem_start.zip(mem_end));
+        self.set_pref_window(bdf, pref_range);
+        self.set_io_window(bdf, io_start.zip(io_end));
+
+        debug!(
+            "  bridge buses [{:#04x}, {:#04x}], IO {:#x?}, MEM {:#x?}, PREF {:#x?}",
+            secondary,
+            subordinate,
+            io_start.zip(io_end),
+            mem_start.zip(mem_end),
+            pref_range,
+        );
+    }
+
+    fn set_bus_numbers(&self, bdf: DeviceFunction, secondary: usize, subordinate: usize) {
+        let old = self.config.read(bdf, PCI_BRIDGE_BUS_NUMBERS);
+        let value = (old & 0xff00_0000)
+            | ((subordinate as u32 & 0xff) << 16)
+            | ((secondary as u32 & 0xff) << 8)
+            | bdf.bus as u32;
+        self.config.write(bdf, PCI_BRIDGE_BUS_NUMBERS, value);
+    }
+
+    /// Programs the non-prefetchable memory window of a bridge, an empty
+    /// range disables it.
+    fn set_mem_window(&self, bdf: DeviceFunction, range: Option<(u64, u64)>) {
+        let value = match range {
+            Some((start, end)) if start < end => {
+                let base = (start >> 16) as u32 & 0xfff0;
+                let limit = ((end - 1) >> 16) as u32 & 0xfff0;
+                base | (limit << 16)
+            }
+            _ => 0x0000_fff0,
+        };
+        self.config.write(bdf, PCI_BRIDGE_MEM_BASE_LIMIT, value);
+    }
+
+    /// Programs the I/O window of a bridge, an empty range disables it.
+    fn set_io_window(&self, bdf: DeviceFunction, range: Option<(u64, u64)>) {
+        let (value, upper) = match range {
+            Some((start, end)) if start < end => {
+                let last = end - 1;
+                let base = (start >> 8) as u32 & 0xf0;
+                let limit = (last >> 8) as u32 & 0xf0;
+                (
+                    base | (limit << 8),
+                    ((start >> 16) as u32 & 0xffff) | ((last >> 16) << 16) as u32,
+                )
+            }
+            _ => (0x0000_00f0, 0),
+        };
+        self.config.write(bdf, PCI_BRIDGE_IO_BASE_LIMIT, value);
+        self.config.write(bdf, PCI_BRIDGE_IO_UPPER, upper);
+    }
+
+    /// Programs the 64-bit prefetchable memory window of a bridge, an empty
+    /// range disables it.
+    fn set_pref_window(&self, bdf: DeviceFunction, range: Option<(u64, u64)>) {
+        let (value, base_upper, limit_upper) = match range {
+            Some((start, end)) if start < end => {
+                let last = end - 1;
+                let base = (start >> 16) as u32 & 0xfff0;
+                let limit = (last >> 16) as u32 & 0xfff0;
+                (
+                    base | (limit << 16),
+                    (start >> 32) as u32,
+                    (last >> 32) as u32,
+                )
+            }
+            _ => (0x0000_fff0, 0, 0),
+        };
+        self.config.write(bdf, PCI_BRIDGE_PREF_BASE_LIMIT, value);
+        self.config
+            .write(bdf, PCI_BRIDGE_PREF_BASE_UPPER, base_upper);
+        self.config
+            .write(bdf, PCI_BRIDGE_PREF_LIMIT_UPPER, limit_upper);
+    }
+}
+
+impl PciHost {
+    /// Stops a function from doing DMA and raising interrupts, before its
+    /// driver is dropped. Its BARs stay decoded for the driver to reset it.
+    fn quiesce(&mut self, bdf: DeviceFunction) {
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(
+            bdf,
+            (cmd - Command::BUS_MASTER) | Command::INTERRUPT_DISABLE,
+        );
+        self.disable_msi(bdf);
+    }
+
+    /// Disables the function, and gives back the BAR ranges assigned to it.
+    ///
+    /// This only uses the recorded assignments, so it also works after the
+    /// function has been surprise removed.
+    fn release_bars(&mut self, bdf: DeviceFunction) {
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(
+            bdf,
+            cmd - Command::IO_SPACE - Command::MEMORY_SPACE - Command::BUS_MASTER,
+        );
+        let mut i = 0;
+        while i < self.assigned.len() {
+            if self.assigned[i].bdf != bdf {
+                i += 1;
+                continue;
+            }
+            let bar = self.assigned.swap_remove(i);
+            debug!(
+                "  release {:?} [{:#x}, {:#x})",
+                bar.space,
+                bar.addr,
+                bar.addr + bar.size
+            );
+            if let Some(window) = self.window(bar.space) {
+                window.free(bar.addr, bar.size, bar.bridge);
+            }
+        }
+    }
+}
+
+impl AllDevices {
+    pub(crate) fn probe_bus_devices(&mut self, args: &InitArgs, deferred: &mut DeferredProbes) {
+        let mut host = PciHost::new(args);
+        host.scan(self, deferred);
+        self.pci = Some(host);
+    }
+
+    /// Quiesces the PCI function of a device that is being unbound.
+    pub(crate) fn quiesce_bus_device(&mut self, location: &DeviceLocation) {
+        if let (Some(host), Some(bdf)) = (self.pci.as_mut(), pci_bdf(location)) {
+            host.quiesce(bdf);
+        }
+    }
+
+    /// Releases a function whose deferred probe has been given up: its
+    /// interrupts, its BARs, and its IOMMU domain.
+    pub(crate) fn release_pci_function(&mut self, bdf: DeviceFunction, irq: PciIrq) {
+        if let Some(host) = self.pci.as_mut() {
+            host.release_irq(bdf, irq);
+            host.release_bars(bdf);
+        }
+        #[cfg(feature = "iommu")]
+        crate::dma::iommu::detach(&pci_location(bdf));
+    }
+
+    /// Releases the PCI function of a device after its driver is dropped.
+    pub(crate) fn release_bus_device(&mut self, location: &DeviceLocation) {
+        let irq = self.scanned_at(location).and_then(|dev| dev.irq);
+        if let (Some(host), Some(bdf)) = (self.pci.as_mut(), pci_bdf(location)) {
+            host.release_bars(bdf);
+            if let Some(DeviceIrq::Msi { base, count }) = irq {
+                host.free_msi_irqs(base, count);
+            }
+        }
+        #[cfg(feature = "iommu")]
+        crate::dma::iommu::detach(location);
+    }
+}
+
+pub(crate) fn pci_location(bdf: DeviceFunction) -> DeviceLocation {
+    DeviceLocation::Pci {
+        bus: bdf.bus,
+        device: bdf.device,
+        function: bdf.function,
+    }
+}
+
+fn pci_bdf(location: &DeviceLocation) -> Option<DeviceFunction> {
+    match *location {
+        DeviceLocation::Pci {
+            bus,
+            device,
+            function,
+        } => Some(DeviceFunction {
+            bus,
+            device,
+            function,
+        }),
+        _ => None,
+    }
+}
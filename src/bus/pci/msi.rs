@@ -0,0 +1,224 @@
+//! Interrupt setup of PCI functions: MSI-X, MSI, or legacy INTx.
+
+use axhal::mem::phys_to_virt;
+use driver_pci::{BarInfo, Command, DeviceFunction};
+
+use super::PciHost;
+
+const PCI_INTERRUPT_LINE: u16 = 0x3c;
+const PCI_INTERRUPT_PIN: u16 = 0x3d;
+
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+const MSI_CTRL_64BIT: u16 = 1 << 7;
+const MSIX_CTRL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: usize = 16;
+
+/// Maximum number of vectors allocated to one PCI function.
+const PCI_MAX_VECTORS: usize = 8;
+
+/// Describes how the platform delivers message signaled interrupts.
+///
+/// A device raises IRQ `n` by writing `n` to the doorbell address. This
+/// matches the x86 local APIC (where `n` is the vector), the ARM GICv2m frame
+/// (where `n` is the SPI number), and the RISC-V IMSIC (where `n` is the
+/// interrupt identity).
+#[derive(Debug, Clone, Copy)]
+pub struct MsiDomain {
+    /// Physical address that devices write to.
+    pub doorbell: u64,
+    /// The first IRQ number available to PCI functions.
+    pub irq_base: usize,
+    /// Number of IRQs available to PCI functions.
+    pub irq_count: usize,
+}
+
+impl MsiDomain {
+    /// Local APIC of the bootstrap processor, using vectors `0x40..0xe0`.
+    pub const X86_LAPIC: Self = Self {
+        doorbell: 0xfee0_0000,
+        irq_base: 0x40,
+        irq_count: 0xa0,
+    };
+}
+
+/// The interrupt set up for a PCI function before its driver is probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciIrq {
+    /// The function does not use interrupts.
+    None,
+    /// Legacy INTx. `pin` is 1 for INTA# through 4 for INTD#, `line` is the
+    /// Interrupt Line register if firmware has set it.
+    Intx { pin: u8, line: Option<u8> },
+    /// MSI with `count` consecutive IRQs starting from `base`.
+    Msi { base: usize, count: usize },
+    /// MSI-X, table entries `0..count` raise IRQs `base..base + count`.
+    MsiX { base: usize, count: usize },
+}
+
+impl PciHost {
+    /// Sets up the interrupt of a function, preferring MSI-X over MSI over
+    /// INTx. MSI and MSI-X are only used if the platform has an
+    /// [`MsiDomain`] and `msi` is set, i.e. the drivers of the function
+    /// program the vectors they are given.
+    pub(super) fn setup_irq(&mut self, bdf: DeviceFunction, msi: bool) -> PciIrq {
+        if msi && self.msi.is_some() {
+            if let Some(irq) = self
+                .find_capability(bdf, PCI_CAP_ID_MSIX)
+                .and_then(|cap| self.setup_msix(bdf, cap))
+            {
+                return irq;
+            }
+            if let Some(irq) = self
+                .find_capability(bdf, PCI_CAP_ID_MSI)
+                .and_then(|cap| self.setup_msi(bdf, cap))
+            {
+                return irq;
+            }
+        }
+
+        match self.config.read8(bdf, PCI_INTERRUPT_PIN) {
+            0 => PciIrq::None,
+            pin => {
+                let line = self.config.read8(bdf, PCI_INTERRUPT_LINE);
+                PciIrq::Intx {
+                    pin,
+                    line: (line != 0 && line != 0xff).then_some(line),
+                }
+            }
+        }
+    }
+
+    /// Disables MSI or MSI-X of a function and returns its IRQs, used when no
+    /// driver claims the function.
+    pub(super) fn release_irq(&mut self, bdf: DeviceFunction, irq: PciIrq) {
+        if let PciIrq::Msi { base, count } | PciIrq::MsiX { base, count } = irq {
+            self.disable_msi(bdf);
+            self.free_msi_irqs(base, count);
+        }
+    }
+
+    /// Gives back IRQs allocated from the MSI domain, after MSI and MSI-X
+    /// of the function have been disabled.
+    pub(super) fn free_msi_irqs(&mut self, base: usize, count: usize) {
+        if let Some(irqs) = self.msi_irqs.as_mut() {
+            irqs.free(base as u64, count as u64, None);
+        }
+    }
+
+    /// Disables both MSI and MSI-X of a function.
+    pub(super) fn disable_msi(&mut self, bdf: DeviceFunction) {
+        for (cap_id, enable) in [
+            (PCI_CAP_ID_MSI, MSI_CTRL_ENABLE),
+            (PCI_CAP_ID_MSIX, MSIX_CTRL_ENABLE),
+        ] {
+            if let Some(cap) = self.find_capability(bdf, cap_id) {
+                let ctrl = self.config.read16(bdf, cap + 2);
+                self.config.write16(bdf, cap + 2, ctrl & !enable);
+            }
+        }
+    }
+
+    /// Walks the capability list, returns the offset of the capability `id`.
+    fn find_capability(&self, bdf: DeviceFunction, id: u8) -> Option<u16> {
+        self.config
+            .capabilities(bdf)
+            .find(|&(cap_id, _)| cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Allocates `count` consecutive IRQs whose first number is a multiple of
+    /// `align`, returns the first IRQ and the doorbell address.
+    fn alloc_msi_irqs(&mut self, count: usize, align: usize) -> Option<(usize, u64)> {
+        let domain = self.msi?;
+        let Some(base) = self
+            .msi_irqs
+            .as_mut()?
+            .alloc_aligned(count as u64, align as u64, None)
+        else {
+            warn!("  no MSI IRQs left for {} vectors", count);
+            return None;
+        };
+        Some((base as usize, domain.doorbell))
+    }
+
+    fn setup_msix(&mut self, bdf: DeviceFunction, cap: u16) -> Option<PciIrq> {
+        let ctrl = self.config.read16(bdf, cap + 2);
+        let table_size = (ctrl & 0x7ff) as usize + 1;
+        let table = self.config.read(bdf, cap + 4);
+        let (bir, offset) = ((table & 0x7) as u8, (table & !0x7) as u64);
+        let BarInfo::Memory { address, .. } = self.root.bar_info(bdf, bir).ok()? else {
+            return None;
+        };
+        if address == 0 {
+            return None;
+        }
+
+        let count = table_size.min(PCI_MAX_VECTORS);
+        let (base, doorbell) = self.alloc_msi_irqs(count, 1)?;
+
+        // Mask the whole function while the table is being programmed.
+        self.config.write16(
+            bdf,
+            cap + 2,
+            ctrl | MSIX_CTRL_ENABLE | MSIX_CTRL_FUNCTION_MASK,
+        );
+        let table_vaddr = phys_to_virt(((address + offset) as usize).into()).as_usize();
+        for i in 0..table_size {
+            let entry = (table_vaddr + i * MSIX_ENTRY_SIZE) as *mut u32;
+            unsafe {
+                if i < count {
+                    entry.write_volatile(doorbell as u32);
+                    entry.add(1).write_volatile((doorbell >> 32) as u32);
+                    entry.add(2).write_volatile((base + i) as u32);
+                    entry.add(3).write_volatile(0); // unmasked
+                } else {
+                    entry.add(3).write_volatile(1); // masked
+                }
+            }
+        }
+        self.config.write16(
+            bdf,
+            cap + 2,
+            (ctrl | MSIX_CTRL_ENABLE) & !MSIX_CTRL_FUNCTION_MASK,
+        );
+        self.disable_intx(bdf);
+        Some(PciIrq::MsiX { base, count })
+    }
+
+    fn setup_msi(&mut self, bdf: DeviceFunction, cap: u16) -> Option<PciIrq> {
+        let ctrl = self.config.read16(bdf, cap + 2);
+        let is_64bit = ctrl & MSI_CTRL_64BIT != 0;
+        let doorbell = self.msi?.doorbell;
+        if !is_64bit && doorbell >> 32 != 0 {
+            return None;
+        }
+
+        // Multiple messages must be a power of two, and aligned so that the
+        // device can put the message index in the low bits of the data.
+        let count = (1usize << ((ctrl >> 1) & 0x7)).min(PCI_MAX_VECTORS);
+        let (base, doorbell) = self.alloc_msi_irqs(count, count)?;
+
+        self.config.write(bdf, cap + 4, doorbell as u32);
+        let data_offset = if is_64bit {
+            self.config.write(bdf, cap + 8, (doorbell >> 32) as u32);
+            cap + 12
+        } else {
+            cap + 8
+        };
+        self.config.write16(bdf, data_offset, base as u16);
+        let mme = (count.trailing_zeros() as u16) << 4;
+        self.config
+            .write16(bdf, cap + 2, (ctrl & !(0x7 << 4)) | mme | MSI_CTRL_ENABLE);
+        self.disable_intx(bdf);
+        Some(PciIrq::Msi { base, count })
+    }
+
+    fn disable_intx(&mut self, bdf: DeviceFunction) {
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(bdf, cmd | Command::INTERRUPT_DISABLE);
+    }
+}
@@ -0,0 +1,61 @@
+//! Suspend, resume and shutdown of PCI functions.
+
+use driver_pci::{Command, DeviceFunction};
+
+use super::{pci_bdf, PciHost, PciIoBar, VirtIoCommonCfg};
+use crate::{AllDevices, DeviceLocation};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Device IDs below it are transitional devices with the legacy interface.
+const VIRTIO_MODERN_DEVICE_ID: u16 = 0x1040;
+/// Offset of the device status register in the legacy I/O BAR.
+const VIRTIO_LEGACY_STATUS: u32 = 0x12;
+
+impl PciHost {
+    /// Stops DMA of a function.
+    fn suspend(&mut self, bdf: DeviceFunction) {
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(bdf, cmd - Command::BUS_MASTER);
+    }
+
+    /// Restarts DMA of a function stopped by [`suspend`](Self::suspend).
+    fn resume(&mut self, bdf: DeviceFunction) {
+        let (_status, cmd) = self.root.get_status_command(bdf);
+        self.root.set_command(bdf, cmd | Command::BUS_MASTER);
+    }
+
+    /// Resets virtio devices, then disables DMA and interrupts.
+    fn shutdown(&mut self, bdf: DeviceFunction) {
+        let (vendor_id, device_id) = (self.config.read16(bdf, 0x00), self.config.read16(bdf, 0x02));
+        if vendor_id == VIRTIO_VENDOR_ID {
+            if device_id >= VIRTIO_MODERN_DEVICE_ID {
+                if let Some(common) = VirtIoCommonCfg::new(&mut self.root, bdf) {
+                    common.reset();
+                }
+            } else if let Some(io) = PciIoBar::new(&mut self.root, bdf, 0) {
+                io.write8(VIRTIO_LEGACY_STATUS, 0);
+            }
+        }
+        self.quiesce(bdf);
+    }
+}
+
+impl AllDevices {
+    pub(crate) fn suspend_bus_device(&mut self, location: &DeviceLocation) {
+        if let (Some(host), Some(bdf)) = (self.pci.as_mut(), pci_bdf(location)) {
+            host.suspend(bdf);
+        }
+    }
+
+    pub(crate) fn resume_bus_device(&mut self, location: &DeviceLocation) {
+        if let (Some(host), Some(bdf)) = (self.pci.as_mut(), pci_bdf(location)) {
+            host.resume(bdf);
+        }
+    }
+
+    pub(crate) fn shutdown_bus_device(&mut self, location: &DeviceLocation) {
+        if let (Some(host), Some(bdf)) = (self.pci.as_mut(), pci_bdf(location)) {
+            host.shutdown(bdf);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! The common configuration structure of modern virtio PCI functions.
+//!
+//! virtio-drivers keeps it private, but the bus needs it to reset devices at
+//! shutdown, and the virtio driver to point the queues at their MSI-X
+//! vectors.
+
+use axhal::mem::phys_to_virt;
+use driver_pci::{BarInfo, DeviceFunction, PciRoot};
+
+use super::PciConfig;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+/// `cfg_type` of the virtio common configuration capability.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+
+// Offsets in the common configuration structure.
+const VIRTIO_COMMON_MSIX_CONFIG: usize = 0x10;
+const VIRTIO_COMMON_NUM_QUEUES: usize = 0x12;
+const VIRTIO_COMMON_STATUS: usize = 0x14;
+const VIRTIO_COMMON_QUEUE_SELECT: usize = 0x16;
+const VIRTIO_COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+
+/// Read back from a vector register if the device couldn't use the vector.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// The common configuration structure of a modern virtio PCI function.
+pub(crate) struct VirtIoCommonCfg {
+    vaddr: usize,
+}
+
+impl VirtIoCommonCfg {
+    /// Finds the common configuration structure of the function, `None` if
+    /// it has none or its BAR is unassigned.
+    pub fn new(root: &mut PciRoot, bdf: DeviceFunction) -> Option<Self> {
+        let config = PciConfig::ecam();
+        let cap = config
+            .capabilities(bdf)
+            .filter(|&(id, _)| id == PCI_CAP_ID_VENDOR)
+            .map(|(_, offset)| offset)
+            .find(|&offset| config.read8(bdf, offset + 3) == VIRTIO_PCI_CAP_COMMON_CFG)?;
+        let bar = config.read8(bdf, cap + 4);
+        let offset = config.read(bdf, cap + 8) as u64;
+        match root.bar_info(bdf, bar).ok()? {
+            BarInfo::Memory { address, .. } if address != 0 => {
+                let paddr = (address + offset) as usize;
+                Some(Self {
+                    vaddr: phys_to_virt(paddr.into()).as_usize(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { ((self.vaddr + offset) as *const u16).read_volatile() }
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        unsafe { ((self.vaddr + offset) as *mut u16).write_volatile(value) }
+    }
+
+    /// Resets the device by writing 0 to `device_status`.
+    pub fn reset(&self) {
+        unsafe { ((self.vaddr + VIRTIO_COMMON_STATUS) as *mut u8).write_volatile(0) }
+    }
+
+    /// Points the configuration change interrupt at MSI-X table entry 0,
+    /// and the queues at the following `count - 1` entries, sharing them
+    /// round-robin if there are more queues. With a single entry, everything
+    /// uses entry 0.
+    ///
+    /// It must be called after the driver has set up its queues, as
+    /// resetting the device forgets the vectors. Returns `false` if the
+    /// device refused a vector.
+    pub fn set_msix_vectors(&self, count: usize) -> bool {
+        let mut ok = true;
+        let mut set = |offset: usize, vector: u16| {
+            self.write16(offset, vector);
+            ok &= self.read16(offset) != VIRTIO_MSI_NO_VECTOR;
+        };
+        set(VIRTIO_COMMON_MSIX_CONFIG, 0);
+        for queue in 0..self.read16(VIRTIO_COMMON_NUM_QUEUES) {
+            let vector = match count {
+                0 | 1 => 0,
+                _ => 1 + queue as usize % (count - 1),
+            };
+            self.write16(VIRTIO_COMMON_QUEUE_SELECT, queue);
+            set(VIRTIO_COMMON_QUEUE_MSIX_VECTOR, vector as u16);
+        }
+        ok
+    }
+}
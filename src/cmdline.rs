@@ -0,0 +1,104 @@
+//! Boot-time driver selection and options from the kernel command line.
+//!
+//! Only the parameters starting with `axdriver.` are recognized, others are
+//! ignored:
+//!
+//! - `axdriver.disable=<drivers>`: do not probe these drivers.
+//! - `axdriver.only=<drivers>`: only probe these drivers, in the given order.
+//! - `axdriver.order=<drivers>`: probe these drivers first, in the given order,
+//!   then all other drivers by [priority](crate::DriverProbe::PRIORITY).
+//! - `axdriver.<driver>.<option>=<value>`: an option of one driver, e.g.
+//!   `axdriver.ramdisk.size=32M`. See [`driver_param`].
+//!
+//! `<drivers>` is a comma-separated list of driver names, e.g.
+//! `virtio-blk,e1000`.
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::{drivers::sorted_drivers, DriverEntry, DRIVERS};
+
+const PREFIX: &str = "axdriver.";
+
+/// The command line given to [`init_drivers_with`](crate::init_drivers_with).
+static CMDLINE_PTR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static CMDLINE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Saves the command line, and warns about unknown driver names in it.
+pub(crate) fn init(cmdline: &'static str) {
+    CMDLINE_LEN.store(cmdline.len(), Ordering::Release);
+    CMDLINE_PTR.store(cmdline.as_ptr() as *mut u8, Ordering::Release);
+
+    for key in ["disable", "only", "order"] {
+        for name in names(key) {
+            if find_driver(name).is_none() {
+                warn!("axdriver.{}: unknown driver {:?}", key, name);
+            }
+        }
+    }
+}
+
+fn cmdline() -> &'static str {
+    let ptr = CMDLINE_PTR.load(Ordering::Acquire);
+    if ptr.is_null() {
+        return "";
+    }
+    let len = CMDLINE_LEN.load(Ordering::Acquire);
+    // SAFETY: `ptr` and `len` come from a `&'static str` saved by `init`.
+    unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+}
+
+/// Returns `(key, value)` of all `axdriver.<key>=<value>` parameters.
+fn params() -> impl Iterator<Item = (&'static str, &'static str)> {
+    cmdline()
+        .split_ascii_whitespace()
+        .filter_map(|param| param.strip_prefix(PREFIX)?.split_once('='))
+}
+
+/// Returns the driver names listed in all `axdriver.<key>=` parameters.
+fn names(key: &'static str) -> impl Iterator<Item = &'static str> {
+    params()
+        .filter(move |&(k, _)| k == key)
+        .flat_map(|(_, v)| v.split(','))
+        .filter(|name| !name.is_empty())
+}
+
+fn find_driver(name: &str) -> Option<&'static DriverEntry> {
+    DRIVERS.iter().find(|driver| driver.name == name)
+}
+
+/// Returns all drivers to probe, in the order they should be probed.
+pub(crate) fn drivers() -> impl Iterator<Item = &'static DriverEntry> {
+    let only = names("only").next().is_some();
+    let listed = names(if only { "only" } else { "order" }).filter_map(find_driver);
+    let rest = sorted_drivers()
+        .filter(move |driver| !only && !names("order").any(|name| name == driver.name));
+    listed
+        .chain(rest)
+        .filter(|driver| !names("disable").any(|name| name == driver.name))
+}
+
+/// Returns the value of the `axdriver.<driver>.<key>=<value>` parameter on
+/// the command line, e.g. `driver_param("ramdisk", "size")`.
+///
+/// If the parameter is given more than once, the last one takes effect.
+pub fn driver_param(driver: &str, key: &str) -> Option<&'static str> {
+    params()
+        .filter(|(k, _)| k.split_once('.') == Some((driver, key)))
+        .map(|(_, v)| v)
+        .last()
+}
+
+/// Parses a size such as `4096`, `0x1000`, `64K`, `16M` or `1G`.
+pub(crate) fn parse_size(s: &str) -> Option<usize> {
+    let (num, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let num = match num.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => num.parse().ok()?,
+    };
+    num.checked_mul(1 << shift)
+}
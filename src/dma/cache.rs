@@ -0,0 +1,275 @@
+//! CPU cache maintenance for platforms where DMA doesn't snoop the caches.
+//!
+//! On such platforms, a streaming buffer is cleaned before the device reads
+//! it and invalidated before the CPU reads what the device has written, and
+//! coherent allocations come from a pool of memory that the platform has
+//! mapped uncached.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::pool::SlotPool;
+use super::{DmaAddr, DmaDirection, DmaMask, PAGE_SIZE};
+use crate::prelude::*;
+
+/// Up to 16 MiB of uncached pages.
+type UncachedPages = SlotPool<PAGE_SIZE, 64>;
+
+/// Whether DMA of the platform is coherent with the CPU caches, given to
+/// [`init_drivers_with`](crate::init_drivers_with).
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DmaCoherency {
+    /// Devices snoop the CPU caches, no maintenance is needed. This is the
+    /// case on x86 and most virtual machines.
+    #[default]
+    Coherent,
+    /// Devices bypass the CPU caches.
+    NonCoherent {
+        /// Memory that the platform has mapped uncached, coherent
+        /// allocations are made from it. If not given, coherent allocations
+        /// fail, and only streaming DMA can be used.
+        uncached: Option<UncachedWindow>,
+    },
+}
+
+/// A range of memory mapped uncached by the platform.
+#[derive(Debug, Clone, Copy)]
+pub struct UncachedWindow {
+    /// Physical address of the range, it must be page-aligned.
+    pub paddr: usize,
+    /// Where the range is mapped uncached.
+    pub vaddr: usize,
+    /// Size of the range in bytes, at most 16 MiB are used.
+    pub size: usize,
+}
+
+static NON_COHERENT: AtomicBool = AtomicBool::new(false);
+static UNCACHED: Mutex<Option<UncachedPages>> = Mutex::new(None);
+
+/// Sets the coherency of the platform.
+pub(crate) fn init(coherency: &DmaCoherency) {
+    let DmaCoherency::NonCoherent { uncached } = coherency else {
+        return;
+    };
+    info!("DMA: non-coherent, CPU caches are maintained by drivers");
+    NON_COHERENT.store(true, Ordering::Release);
+    match uncached {
+        Some(window) => {
+            let pages = UncachedPages::new(window.paddr, window.vaddr, window.size);
+            info!(
+                "DMA: uncached memory at [PA:{:#x}, PA:{:#x})",
+                window.paddr,
+                window.paddr + pages.size()
+            );
+            *UNCACHED.lock() = Some(pages);
+        }
+        None => warn!("DMA: no uncached memory, coherent allocations will fail"),
+    }
+}
+
+/// Returns whether DMA bypasses the CPU caches.
+pub fn is_non_coherent() -> bool {
+    NON_COHERENT.load(Ordering::Acquire)
+}
+
+/// Allocates pages from the uncached pool, returns `None` if there is no
+/// pool.
+pub(super) fn alloc_uncached(
+    size: usize,
+    mask: DmaMask,
+) -> Option<DevResult<(DmaAddr, NonNull<u8>)>> {
+    let mut pool = UNCACHED.lock();
+    let pool = pool.as_mut()?;
+    let Some(dma_addr) = pool.alloc(size, PAGE_SIZE) else {
+        warn!("DMA: no uncached memory left for {} bytes", size);
+        return Some(Err(DevError::NoMemory));
+    };
+    if !mask.contains(dma_addr, size) {
+        pool.free(dma_addr, size);
+        warn!(
+            "DMA: uncached memory is out of the device's reach ({:?})",
+            mask
+        );
+        return Some(Err(DevError::NoMemory));
+    }
+    Some(
+        NonNull::new(pool.virt(dma_addr))
+            .map(|vaddr| (dma_addr, vaddr))
+            .ok_or(DevError::NoMemory),
+    )
+}
+
+/// Returns the DMA address of `vaddr` if it's in the uncached pool.
+pub(super) fn uncached_to_dma(vaddr: usize) -> Option<DmaAddr> {
+    UNCACHED.lock().as_ref()?.dma_addr(vaddr)
+}
+
+/// Frees pages allocated by [`alloc_uncached`], returns `false` if they are
+/// not from the uncached pool.
+pub(super) fn free_uncached(dma_addr: DmaAddr, size: usize) -> bool {
+    match UNCACHED.lock().as_mut() {
+        Some(pool) if pool.contains(dma_addr) => {
+            pool.free(dma_addr, size);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Makes the CPU's writes to `[vaddr, vaddr + size)` visible to the device
+/// before it accesses the buffer.
+///
+/// It's called by [`map`](super::map). Drivers that keep a streaming buffer
+/// mapped across transfers must call it before each transfer.
+pub fn sync_for_device(vaddr: usize, size: usize, dir: DmaDirection) {
+    if !is_non_coherent() {
+        return;
+    }
+    match dir {
+        DmaDirection::ToDevice => arch::clean(vaddr, size),
+        // Dirty lines must not be written back over what the device writes.
+        DmaDirection::FromDevice | DmaDirection::Bidirectional => arch::flush(vaddr, size),
+    }
+}
+
+/// Makes the device's writes to `[vaddr, vaddr + size)` visible to the CPU
+/// after it has accessed the buffer.
+///
+/// It's called by [`unmap`](super::unmap). Drivers that keep a streaming
+/// buffer mapped across transfers must call it after each transfer.
+///
+/// The cache lines that the buffer only partly covers are cleaned before
+/// they are invalidated, so that the data around the buffer is kept. If the
+/// CPU has written that data during the transfer, the part of the buffer in
+/// these lines may be stale, so buffers written by the device should be
+/// aligned to cache lines.
+pub fn sync_for_cpu(vaddr: usize, size: usize, dir: DmaDirection) {
+    if !is_non_coherent() {
+        return;
+    }
+    // Lines may have been speculatively loaded during the transfer.
+    if dir != DmaDirection::ToDevice {
+        arch::invalidate(vaddr, size);
+    }
+}
+
+/// Calls `op` for each cache line in `[vaddr, vaddr + size)`.
+#[allow(dead_code)]
+fn for_each_line(vaddr: usize, size: usize, line_size: usize, mut op: impl FnMut(usize)) {
+    let mut line = vaddr & !(line_size - 1);
+    while line < vaddr + size {
+        op(line);
+        line += line_size;
+    }
+}
+
+/// Calls `whole` for each cache line in `[vaddr, vaddr + size)`, and
+/// `partial` for the head and tail lines that also hold memory outside it.
+#[allow(dead_code)]
+fn for_each_line_split(
+    vaddr: usize,
+    size: usize,
+    line_size: usize,
+    mut whole: impl FnMut(usize),
+    mut partial: impl FnMut(usize),
+) {
+    for_each_line(vaddr, size, line_size, |line| {
+        if line < vaddr || line + line_size > vaddr + size {
+            partial(line)
+        } else {
+            whole(line)
+        }
+    });
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use core::arch::asm;
+
+    /// The smallest data cache line size, from `CTR_EL0.DminLine`.
+    fn line_size() -> usize {
+        let ctr: u64;
+        unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+        4 << ((ctr >> 16) & 0xf)
+    }
+
+    pub fn clean(vaddr: usize, size: usize) {
+        super::for_each_line(vaddr, size, line_size(), |line| unsafe {
+            asm!("dc cvac, {}", in(reg) line)
+        });
+        unsafe { asm!("dsb sy") };
+    }
+
+    /// Partial lines are cleaned and invalidated, not to discard the data
+    /// around the range.
+    pub fn invalidate(vaddr: usize, size: usize) {
+        super::for_each_line_split(
+            vaddr,
+            size,
+            line_size(),
+            |line| unsafe { asm!("dc ivac, {}", in(reg) line) },
+            |line| unsafe { asm!("dc civac, {}", in(reg) line) },
+        );
+        unsafe { asm!("dsb sy") };
+    }
+
+    pub fn flush(vaddr: usize, size: usize) {
+        super::for_each_line(vaddr, size, line_size(), |line| unsafe {
+            asm!("dc civac, {}", in(reg) line)
+        });
+        unsafe { asm!("dsb sy") };
+    }
+}
+
+/// Uses the `Zicbom` extension, encoded with `.insn` so that it builds with
+/// assemblers that don't know it.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+mod arch {
+    use core::arch::asm;
+
+    /// The cache block size, 64 bytes on all known implementations.
+    const LINE_SIZE: usize = 64;
+
+    pub fn clean(vaddr: usize, size: usize) {
+        // cbo.clean
+        super::for_each_line(vaddr, size, LINE_SIZE, |line| unsafe {
+            asm!(".insn i 0x0f, 2, x0, {}, 1", in(reg) line)
+        });
+        unsafe { asm!("fence rw, rw") };
+    }
+
+    /// Partial lines are flushed, not to discard the data around the range.
+    pub fn invalidate(vaddr: usize, size: usize) {
+        // cbo.inval and cbo.flush
+        super::for_each_line_split(
+            vaddr,
+            size,
+            LINE_SIZE,
+            |line| unsafe { asm!(".insn i 0x0f, 2, x0, {}, 0", in(reg) line) },
+            |line| unsafe { asm!(".insn i 0x0f, 2, x0, {}, 2", in(reg) line) },
+        );
+        unsafe { asm!("fence rw, rw") };
+    }
+
+    pub fn flush(vaddr: usize, size: usize) {
+        // cbo.flush
+        super::for_each_line(vaddr, size, LINE_SIZE, |line| unsafe {
+            asm!(".insn i 0x0f, 2, x0, {}, 2", in(reg) line)
+        });
+        unsafe { asm!("fence rw, rw") };
+    }
+}
+
+/// DMA is always coherent on other architectures, e.g. x86.
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+)))]
+mod arch {
+    pub fn clean(_vaddr: usize, _size: usize) {}
+    pub fn invalidate(_vaddr: usize, _size: usize) {}
+    pub fn flush(_vaddr: usize, _size: usize) {}
+}
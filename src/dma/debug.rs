@@ -0,0 +1,183 @@
+//! Tracking of DMA allocations and mappings, to catch misuse and leaks.
+//!
+//! With the `dma-debug` feature, every coherent allocation and streaming
+//! mapping is recorded with the device that made it. Frees and unmaps are
+//! checked against the records: double frees, unknown addresses and size or
+//! direction mismatches are logged as errors and ignored. What a device
+//! still holds is reported when it's unbound, and for all devices at
+//! [shutdown](crate::AllDevices::shutdown).
+//!
+//! Records are attributed to the device bound to the [`DmaDevice`] they are
+//! made for. The DMA of devices sharing a handle, as in the static device
+//! model with several devices of a category, is recorded without an owner.
+
+use core::fmt;
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::{DmaAddr, DmaDevice, DmaDirection, DmaOwner};
+use crate::{prelude::*, DeviceLocation};
+
+/// Maximum number of outstanding allocations and mappings tracked.
+const MAX_RECORDS: usize = 1024;
+/// Number of freed records kept to tell double frees from unknown addresses.
+const MAX_FREED: usize = 64;
+
+/// What a record is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaKind {
+    /// Memory from [`alloc_coherent`](super::alloc_coherent).
+    Coherent,
+    /// A buffer mapped by [`map`](super::map).
+    Streaming(DmaDirection),
+}
+
+/// An outstanding DMA allocation or mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRecord {
+    pub kind: DmaKind,
+    pub dma_addr: DmaAddr,
+    pub size: usize,
+    /// The handle it was made for.
+    pub dev: DmaDevice,
+    /// The device that made it, if its handle isn't shared.
+    pub owner: Option<DmaOwner>,
+}
+
+struct Tracker {
+    records: ArrayVec<DmaRecord, MAX_RECORDS>,
+    freed: ArrayVec<DmaRecord, MAX_FREED>,
+    /// Whether some records were dropped because the table was full.
+    overflowed: bool,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    records: ArrayVec::new_const(),
+    freed: ArrayVec::new_const(),
+    overflowed: false,
+});
+
+fn describe(owner: Option<DmaOwner>) -> impl fmt::Display {
+    struct Owner(Option<DmaOwner>);
+    impl fmt::Display for Owner {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.0 {
+                Some(owner) => write!(f, "{}", owner),
+                None => write!(f, "unknown device"),
+            }
+        }
+    }
+    Owner(owner)
+}
+
+/// Records a new allocation or mapping made for `dev`.
+pub(super) fn track(dev: DmaDevice, kind: DmaKind, dma_addr: DmaAddr, size: usize) {
+    let owner = dev.owner();
+    let mut tracker = TRACKER.lock();
+    let record = DmaRecord {
+        kind,
+        dma_addr,
+        size,
+        dev,
+        owner,
+    };
+    if tracker.records.try_push(record).is_err() && !tracker.overflowed {
+        warn!(
+            "dma-debug: more than {} DMA records, some are not tracked",
+            MAX_RECORDS
+        );
+        tracker.overflowed = true;
+    }
+}
+
+/// Checks and removes the record of a free or unmap for `dev`, returns
+/// `false` if it must be ignored.
+pub(super) fn untrack(dev: DmaDevice, kind: DmaKind, dma_addr: DmaAddr, size: usize) -> bool {
+    let mut tracker = TRACKER.lock();
+    let is_coherent = |k: DmaKind| (k == DmaKind::Coherent) == (kind == DmaKind::Coherent);
+    let found = tracker
+        .records
+        .iter()
+        .position(|r| r.dma_addr == dma_addr && is_coherent(r.kind));
+    let Some(i) = found else {
+        let double = tracker
+            .freed
+            .iter()
+            .any(|r| r.dma_addr == dma_addr && is_coherent(r.kind));
+        if double {
+            error!("dma-debug: {:?} at {:#x} is freed twice", kind, dma_addr);
+        } else if tracker.overflowed {
+            // It may be one of the records that were not tracked.
+            return true;
+        } else {
+            error!("dma-debug: {:?} at {:#x} is not allocated", kind, dma_addr);
+        }
+        return false;
+    };
+    let record = tracker.records.swap_remove(i);
+    if record.size != size || record.kind != kind {
+        error!(
+            "dma-debug: {:?} of {} bytes at {:#x} by {} is freed as {:?} of {} bytes",
+            record.kind,
+            record.size,
+            dma_addr,
+            describe(record.owner),
+            kind,
+            size
+        );
+    }
+    if record.dev != dev {
+        error!(
+            "dma-debug: {:?} at {:#x} by {} is freed by another device",
+            record.kind,
+            dma_addr,
+            describe(record.owner)
+        );
+    }
+    if tracker.freed.is_full() {
+        tracker.freed.remove(0);
+    }
+    tracker.freed.push(record);
+    true
+}
+
+/// Calls `f` for each outstanding allocation and mapping.
+pub fn for_each_record(mut f: impl FnMut(&DmaRecord)) {
+    TRACKER.lock().records.iter().for_each(|r| f(r));
+}
+
+/// Logs the outstanding DMA of each device, or only of the device at
+/// `location` if given. Returns the number of records.
+pub fn report(location: Option<&DeviceLocation>) -> usize {
+    let tracker = TRACKER.lock();
+    let selected = |r: &DmaRecord| match location {
+        Some(location) => r.owner.is_some_and(|o| o.location == *location),
+        None => true,
+    };
+    let records = &tracker.records;
+    let mut count = 0;
+    for (i, record) in records.iter().enumerate() {
+        // Report each owner at its first record.
+        if !selected(record) || records[..i].iter().any(|r| r.owner == record.owner) {
+            continue;
+        }
+        let held = || records.iter().filter(|r| r.owner == record.owner);
+        warn!(
+            "dma-debug: {} still holds {} DMA buffers ({} bytes):",
+            describe(record.owner),
+            held().count(),
+            held().map(|r| r.size).sum::<usize>()
+        );
+        for r in held() {
+            warn!(
+                "  {:?} [{:#x}, {:#x})",
+                r.kind,
+                r.dma_addr,
+                r.dma_addr + r.size
+            );
+        }
+        count += held().count();
+    }
+    count
+}
@@ -0,0 +1,194 @@
+//! Handles of the devices that do DMA.
+//!
+//! Every allocation and mapping of [`dma`](super) is made for a
+//! [`DmaDevice`], a handle bound to the device when its driver probes it.
+//! HAL adapters whose functions take `self`, such as the e1000 `KernelFunc`,
+//! keep the handle in a field. The `VirtIoHal` and `IxgbeHal` functions take
+//! no `self`, so their adapters carry the index of the handle as a const
+//! parameter, and each device is created with the adapter of its own handle.
+//!
+//! In the static device model, all devices of a category have the same type,
+//! so those whose adapter carries the index share the handle of the category,
+//! see [`DmaDevice::bind_for`]. The DMA of devices sharing a handle can't be
+//! told apart. Adapters that keep the handle in a field get a handle of their
+//! own in both models.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::DmaMask;
+use crate::{prelude::*, DeviceLocation};
+
+/// Number of handles shared by the devices of a category in the static
+/// device model, one for each category.
+pub(crate) const NUM_SHARED: usize = 3;
+/// Maximum number of handles, the shared ones included. The number of the
+/// others is set by the `AXDRIVER_DMA_DEVICES` environment variable at build
+/// time, 8 by default.
+pub const MAX_DMA_DEVICES: usize =
+    NUM_SHARED + crate::structs::parse_usize(env!("AXDRIVER_DMA_DEVICES"));
+/// Maximum number of devices sharing a handle, those of a category in the
+/// static device model.
+const MAX_SHARING: usize = crate::MAX_DEVICES;
+
+/// A device that does DMA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaOwner {
+    /// The driver of the device.
+    pub driver: &'static str,
+    /// Where the device is attached.
+    pub location: DeviceLocation,
+}
+
+impl fmt::Display for DmaOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.driver, self.location)
+    }
+}
+
+type Owners = ArrayVec<DmaOwner, MAX_SHARING>;
+
+const NO_OWNERS: Owners = ArrayVec::new_const();
+
+/// The devices bound to each handle.
+static OWNERS: Mutex<[Owners; MAX_DMA_DEVICES]> = Mutex::new([NO_OWNERS; MAX_DMA_DEVICES]);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// The [`DmaMask`] of each handle, read without a lock on every mapping.
+static MASKS: [AtomicU64; MAX_DMA_DEVICES] = [NO_MASK; MAX_DMA_DEVICES];
+
+/// The handle of a device that does DMA, given to the functions of
+/// [`dma`](super).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaDevice(usize);
+
+impl DmaDevice {
+    /// The handle shared by the NICs in the static device model.
+    pub const NET: Self = Self(0);
+    /// The handle shared by the block devices in the static device model.
+    pub const BLOCK: Self = Self(1);
+    /// The handle shared by the display devices in the static device model.
+    pub const DISPLAY: Self = Self(2);
+
+    /// Returns the handle at `index`, for HAL adapters that carry it as a
+    /// const parameter.
+    pub const fn from_index(index: usize) -> Self {
+        Self(index)
+    }
+
+    /// Returns the index of the handle, less than [`MAX_DMA_DEVICES`].
+    pub const fn index(self) -> usize {
+        self.0
+    }
+
+    /// Binds a free handle to the device at `location`, probed by `driver`.
+    ///
+    /// The handle is given back when the probe fails or the device is
+    /// unbound.
+    pub fn bind(driver: &'static str, location: DeviceLocation) -> DevResult<Self> {
+        let mut owners = OWNERS.lock();
+        let Some(index) = (NUM_SHARED..MAX_DMA_DEVICES).find(|&i| owners[i].is_empty()) else {
+            warn!("DMA: no handle left for {} at {}", driver, location);
+            return Err(DevError::NoMemory);
+        };
+        owners[index].push(DmaOwner { driver, location });
+        Ok(Self(index))
+    }
+
+    /// Binds a handle to a device of category `ty`: its own handle if the
+    /// category uses the dynamic device model, or the handle shared by the
+    /// category if it uses the static one.
+    pub fn bind_for(
+        ty: DeviceType,
+        driver: &'static str,
+        location: DeviceLocation,
+    ) -> DevResult<Self> {
+        let shared = match ty {
+            DeviceType::Net if cfg!(not(net_dyn)) => Some(Self::NET),
+            DeviceType::Block if cfg!(not(block_dyn)) => Some(Self::BLOCK),
+            DeviceType::Display if cfg!(not(display_dyn)) => Some(Self::DISPLAY),
+            _ => None,
+        };
+        let Some(dev) = shared else {
+            return Self::bind(driver, location);
+        };
+        let owner = DmaOwner { driver, location };
+        if OWNERS.lock()[dev.0].try_push(owner).is_err() {
+            warn!("DMA: too many devices share the handle of {}", owner);
+            return Err(DevError::NoMemory);
+        }
+        Ok(dev)
+    }
+
+    /// Restricts the handle to the DMA addresses under `mask`, set by the
+    /// driver when it finds out what the device can reach.
+    ///
+    /// A handle shared by several devices keeps the addresses all of them
+    /// can reach.
+    pub fn set_mask(self, mask: DmaMask) {
+        MASKS[self.0].fetch_and(mask.0, Ordering::Relaxed);
+    }
+
+    /// Returns the DMA addresses the devices of the handle can reach, all of
+    /// them if the driver hasn't [set](Self::set_mask) a mask.
+    pub fn mask(self) -> DmaMask {
+        DmaMask(MASKS[self.0].load(Ordering::Relaxed))
+    }
+
+    /// Returns the device bound to the handle, or `None` if it's unbound or
+    /// shared by several devices.
+    pub fn owner(self) -> Option<DmaOwner> {
+        match OWNERS.lock().get(self.0)?.as_slice() {
+            [owner] => Some(*owner),
+            _ => None,
+        }
+    }
+
+    /// Returns all devices bound to the handle.
+    #[allow(dead_code)]
+    pub(super) fn owners(self) -> Owners {
+        OWNERS.lock().get(self.0).cloned().unwrap_or_default()
+    }
+}
+
+/// Unbinds the device at `location` probed by `driver` from its handle.
+pub(crate) fn release(driver: &str, location: &DeviceLocation) {
+    for (owners, mask) in OWNERS.lock().iter_mut().zip(&MASKS) {
+        owners.retain(|owner| !(owner.driver == driver && owner.location == *location));
+        if owners.is_empty() {
+            mask.store(u64::MAX, Ordering::Relaxed);
+        }
+    }
+}
+
+// Defines `with_dma_offsets!`, generated by the build script with one offset
+// from `NUM_SHARED` for each handle bound by `DmaDevice::bind`.
+include!(concat!(env!("OUT_DIR"), "/dma_devices.rs"));
+
+/// Evaluates `$body` with the const `$index` set to the index of the
+/// [`DmaDevice`] `$dev`, so that a device can be created with the HAL
+/// adapter of its handle, e.g. `VirtIoHalImpl<$index>`.
+///
+/// It's only for handles bound by [`DmaDevice::bind`], each one is a new
+/// instance of the driver.
+macro_rules! with_dma_index {
+    ($dev:expr, $index:ident => $body:expr) => {
+        $crate::dma::with_dma_offsets!($dev.index(), $index => $body)
+    };
+    (@ $i:expr, $index:ident => $body:expr; $($n:literal)*) => {
+        match $i.wrapping_sub($crate::dma::NUM_SHARED) {
+            $($n => {
+                const $index: usize = $crate::dma::NUM_SHARED + $n;
+                $body
+            })*
+            _ => unreachable!(),
+        }
+    };
+}
+
+pub(crate) use {with_dma_index, with_dma_offsets};
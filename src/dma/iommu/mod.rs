@@ -0,0 +1,329 @@
+//! DMA isolation of PCI devices with an IOMMU.
+//!
+//! With an IOMMU given by [`InitArgs::iommu`](crate::InitArgs::iommu), each
+//! PCI function gets its own domain before it's probed: an I/O page table in
+//! which only the memory its driver shares is mapped. [`map`](super::map)
+//! and [`alloc_coherent`](super::alloc_coherent) allocate I/O virtual
+//! addresses (IOVAs) for the pages of the buffer, and hand them to the device
+//! instead of physical addresses. Any other access of the device is blocked
+//! and reported by the IOMMU as a fault.
+//!
+//! DMA memory is only mapped in the domains of the devices bound to the
+//! [`DmaDevice`] it's allocated or mapped for. It's refused if none of them
+//! has a domain, e.g. the device is not attached or the handle is unbound.
+//! Devices sharing a handle in the static device model share their mappings.
+//!
+//! Only Intel VT-d is supported for now, e.g. QEMU's `-device intel-iommu`
+//! on the `q35` machine. virtio devices bypass the IOMMU unless they are
+//! given `iommu_platform=on`.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use self::vtd::VtD;
+use super::pool::SlotPool;
+use super::{DmaAddr, DmaDevice, DmaDirection, DmaMask, PAGE_SIZE};
+use crate::{prelude::*, DeviceLocation};
+
+mod vtd;
+
+/// Start of the IOVA space. IOVAs are kept under 4 GiB for 32-bit devices,
+/// and away from the MSI window at `0xfee0_0000`.
+const IOVA_BASE: DmaAddr = 0x4000_0000;
+/// Up to 1 GiB of IOVA space, in pages.
+type IovaSpace = SlotPool<PAGE_SIZE, 4096>;
+
+/// Maximum number of attached devices.
+const MAX_DOMAINS: usize = 64;
+/// Maximum number of outstanding coherent allocations.
+const MAX_COHERENT: usize = 256;
+
+/// The IOMMU given to [`init_drivers_with`](crate::init_drivers_with).
+#[derive(Debug, Clone, Copy)]
+pub enum IommuArgs {
+    /// An Intel VT-d remapping unit covering all PCI devices.
+    VtD {
+        /// Physical address of the registers, from the ACPI `DMAR` table
+        /// (`0xfed9_0000` in QEMU). They must be in the kernel's mapping.
+        paddr: usize,
+    },
+}
+
+/// The ID of a domain in the IOMMU, starting from 1.
+pub(super) type DomainId = u16;
+
+/// What a device may do with a mapping.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IommuPerm {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl From<DmaDirection> for IommuPerm {
+    fn from(dir: DmaDirection) -> Self {
+        Self {
+            read: dir != DmaDirection::FromDevice,
+            write: dir != DmaDirection::ToDevice,
+        }
+    }
+}
+
+struct Domain {
+    location: DeviceLocation,
+    bus: u8,
+    devfn: u8,
+    id: DomainId,
+    /// Physical address of the page table.
+    pt: usize,
+}
+
+/// A coherent allocation, to translate addresses inside it.
+struct CoherentMap {
+    vaddr: usize,
+    iova: DmaAddr,
+    /// The DMA address without translation.
+    bus_addr: DmaAddr,
+    size: usize,
+}
+
+struct Iommu {
+    unit: VtD,
+    domains: ArrayVec<Domain, MAX_DOMAINS>,
+    next_id: DomainId,
+    iova: IovaSpace,
+    coherent: ArrayVec<CoherentMap, MAX_COHERENT>,
+}
+
+static IOMMU: Mutex<Option<Iommu>> = Mutex::new(None);
+
+/// Enables the IOMMU. Until a device is attached, it can't do DMA.
+pub(crate) fn init(args: &IommuArgs) -> DevResult {
+    let unit = match *args {
+        IommuArgs::VtD { paddr } => VtD::new(paddr)?,
+    };
+    let iova = IovaSpace::new(IOVA_BASE, 0, IovaSpace::MAX_SIZE);
+    info!(
+        "IOMMU: IOVAs at [{:#x}, {:#x})",
+        IOVA_BASE,
+        IOVA_BASE + iova.size()
+    );
+    *IOMMU.lock() = Some(Iommu {
+        unit,
+        domains: ArrayVec::new(),
+        next_id: 1,
+        iova,
+        coherent: ArrayVec::new(),
+    });
+    Ok(())
+}
+
+/// Returns whether DMA addresses are translated by an IOMMU.
+pub fn is_enabled() -> bool {
+    IOMMU.lock().is_some()
+}
+
+fn pci_devfn(location: &DeviceLocation) -> Option<(u8, u8)> {
+    match *location {
+        DeviceLocation::Pci {
+            bus,
+            device,
+            function,
+        } => Some((bus, device << 3 | function)),
+        _ => None,
+    }
+}
+
+/// Gives the PCI device at `location` its own, empty domain.
+pub(crate) fn attach(location: &DeviceLocation) {
+    let mut iommu = IOMMU.lock();
+    let (Some(iommu), Some((bus, devfn))) = (iommu.as_mut(), pci_devfn(location)) else {
+        return;
+    };
+    if iommu.domains.iter().any(|d| d.location == *location) {
+        return;
+    }
+    if iommu.domains.is_full() {
+        warn!("IOMMU: no domain left for {}, it can't do DMA", location);
+        return;
+    }
+    let id = iommu.next_id;
+    match iommu.unit.attach(bus, devfn, id) {
+        Ok(pt) => {
+            debug!("IOMMU: domain {} for {}", id, location);
+            iommu.next_id += 1;
+            iommu.domains.push(Domain {
+                location: *location,
+                bus,
+                devfn,
+                id,
+                pt,
+            });
+        }
+        Err(e) => warn!("IOMMU: failed to attach {}: {:?}", location, e),
+    }
+}
+
+/// Blocks all DMA of the device at `location` and frees its domain.
+pub(crate) fn detach(location: &DeviceLocation) {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return;
+    };
+    if let Some(i) = iommu.domains.iter().position(|d| d.location == *location) {
+        let domain = iommu.domains.swap_remove(i);
+        iommu
+            .unit
+            .detach(domain.bus, domain.devfn, domain.id, domain.pt);
+    }
+}
+
+const fn page_span(addr: usize, size: usize) -> usize {
+    ((addr % PAGE_SIZE) + size.max(1)).next_multiple_of(PAGE_SIZE)
+}
+
+impl Iommu {
+    /// Returns the domains of the devices bound to `dev`.
+    fn domains_of(&self, dev: DmaDevice) -> impl Iterator<Item = &Domain> {
+        let owners = dev.owners();
+        self.domains
+            .iter()
+            .filter(move |d| owners.iter().any(|o| o.location == d.location))
+    }
+
+    /// Maps the pages of `[bus_addr, bus_addr + size)` at a new IOVA, in the
+    /// domains of `dev`.
+    fn map(
+        &mut self,
+        dev: DmaDevice,
+        bus_addr: DmaAddr,
+        size: usize,
+        perm: IommuPerm,
+        mask: DmaMask,
+    ) -> DevResult<DmaAddr> {
+        if self.domains_of(dev).next().is_none() {
+            match dev.owners().first() {
+                Some(owner) => warn!("IOMMU: {} has no domain, DMA refused", owner),
+                None => warn!("IOMMU: DMA handle {} is unbound, DMA refused", dev.index()),
+            }
+            return Err(DevError::BadState);
+        }
+        let span = page_span(bus_addr, size);
+        let base = self.iova.alloc(span, PAGE_SIZE).ok_or_else(|| {
+            warn!("IOMMU: no IOVA space left for {} bytes", size);
+            DevError::NoMemory
+        })?;
+        let iova = base + bus_addr % PAGE_SIZE;
+        if !mask.contains(iova, size) {
+            self.iova.free(base, span);
+            warn!("IOMMU: IOVAs are out of the device's reach ({:?})", mask);
+            return Err(DevError::NoMemory);
+        }
+        let paddr = bus_addr - bus_addr % PAGE_SIZE;
+        let res = self
+            .domains_of(dev)
+            .try_for_each(|d| self.unit.map(d.pt, d.id, base, paddr, span, perm));
+        if let Err(e) = res {
+            self.unmap(dev, iova, size);
+            return Err(e);
+        }
+        Ok(iova)
+    }
+
+    /// Unmaps the pages of `[iova, iova + size)` from the domains of `dev`,
+    /// returns the DMA address they were mapped to.
+    ///
+    /// The IOVAs are freed even if the domains are gone.
+    fn unmap(&mut self, dev: DmaDevice, iova: DmaAddr, size: usize) -> Option<DmaAddr> {
+        if !self.iova.contains(iova) {
+            return None;
+        }
+        let base = iova - iova % PAGE_SIZE;
+        let span = page_span(iova, size);
+        let mut bus_addr = None;
+        for domain in self.domains_of(dev) {
+            bus_addr = bus_addr.or_else(|| self.unit.translate(domain.pt, iova));
+            self.unit.unmap(domain.pt, domain.id, base, span);
+        }
+        self.iova.free(base, span);
+        bus_addr
+    }
+
+    fn translate(&self, iova: DmaAddr) -> Option<DmaAddr> {
+        self.domains
+            .iter()
+            .find_map(|domain| self.unit.translate(domain.pt, iova))
+    }
+}
+
+/// Maps a streaming buffer at `bus_addr` for `dev`, returns its IOVA, or
+/// `None` if there is no IOMMU.
+pub(super) fn map(
+    dev: DmaDevice,
+    bus_addr: DmaAddr,
+    size: usize,
+    dir: DmaDirection,
+    mask: DmaMask,
+) -> Option<DevResult<DmaAddr>> {
+    let mut iommu = IOMMU.lock();
+    Some(iommu.as_mut()?.map(dev, bus_addr, size, dir.into(), mask))
+}
+
+/// Unmaps a streaming buffer of `dev`, returns the DMA address it was mapped
+/// to, or `None` if `iova` is not translated.
+pub(super) fn unmap(dev: DmaDevice, iova: DmaAddr, size: usize) -> Option<DmaAddr> {
+    IOMMU.lock().as_mut()?.unmap(dev, iova, size)
+}
+
+/// Maps a coherent allocation for `dev`, returns its IOVA, or `None` if
+/// there is no IOMMU.
+pub(super) fn map_coherent(
+    dev: DmaDevice,
+    vaddr: usize,
+    bus_addr: DmaAddr,
+    size: usize,
+    mask: DmaMask,
+) -> Option<DevResult<DmaAddr>> {
+    let mut iommu = IOMMU.lock();
+    let iommu = iommu.as_mut()?;
+    if iommu.coherent.is_full() {
+        warn!("IOMMU: more than {} coherent allocations", MAX_COHERENT);
+        return Some(Err(DevError::NoMemory));
+    }
+    let perm = IommuPerm::from(DmaDirection::Bidirectional);
+    Some(iommu.map(dev, bus_addr, size, perm, mask).map(|iova| {
+        iommu.coherent.push(CoherentMap {
+            vaddr,
+            iova,
+            bus_addr,
+            size,
+        });
+        iova
+    }))
+}
+
+/// Unmaps a coherent allocation of `dev`, returns the DMA address it was
+/// mapped to, or `None` if `iova` is not translated.
+pub(super) fn unmap_coherent(dev: DmaDevice, iova: DmaAddr) -> Option<DmaAddr> {
+    let mut iommu = IOMMU.lock();
+    let iommu = iommu.as_mut()?;
+    let i = iommu.coherent.iter().position(|c| c.iova == iova)?;
+    let map = iommu.coherent.swap_remove(i);
+    iommu.unmap(dev, map.iova, map.size);
+    Some(map.bus_addr)
+}
+
+/// Returns the IOVA of `vaddr` if it's in a coherent allocation.
+pub(super) fn coherent_to_iova(vaddr: usize) -> Option<DmaAddr> {
+    let iommu = IOMMU.lock();
+    iommu
+        .as_ref()?
+        .coherent
+        .iter()
+        .find(|c| (c.vaddr..c.vaddr + c.size).contains(&vaddr))
+        .map(|c| c.iova + (vaddr - c.vaddr))
+}
+
+/// Returns the DMA address that `iova` is mapped to.
+pub(super) fn translate(iova: DmaAddr) -> Option<DmaAddr> {
+    IOMMU.lock().as_ref()?.translate(iova)
+}
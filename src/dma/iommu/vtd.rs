@@ -0,0 +1,274 @@
+//! Intel VT-d DMA remapping, as emulated by QEMU's `intel-iommu` device.
+//!
+//! Only legacy-mode translation with second-level page tables and
+//! register-based invalidation is used, which all VT-d implementations
+//! support.
+
+use axalloc::global_allocator;
+use axhal::mem::{phys_to_virt, virt_to_phys};
+
+use super::{DomainId, IommuPerm};
+use crate::dma::{DmaAddr, PAGE_SIZE};
+use crate::prelude::*;
+
+// Remapping hardware registers.
+const VTD_CAP: usize = 0x08;
+const VTD_ECAP: usize = 0x10;
+const VTD_GCMD: usize = 0x18;
+const VTD_GSTS: usize = 0x1c;
+const VTD_RTADDR: usize = 0x20;
+const VTD_CCMD: usize = 0x28;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+/// Status bits that are not one-shot, and must be kept when writing `GCMD`.
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DOMAIN: u64 = 2 << 60;
+const IOTLB_DRAIN: u64 = 3 << 48;
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const PTE_READ: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Number of 8-byte entries in a page table.
+const ENTRIES: usize = PAGE_SIZE / 8;
+
+/// A VT-d remapping unit.
+pub(super) struct VtD {
+    regs: usize,
+    /// Offset of the IOTLB registers.
+    iotlb: usize,
+    /// Physical address of the root table.
+    root: usize,
+    /// Number of page table levels, 3 or 4.
+    levels: usize,
+    /// Whether the unit snoops the CPU caches when walking the tables.
+    coherent: bool,
+}
+
+fn alloc_table() -> DevResult<usize> {
+    let vaddr = global_allocator()
+        .alloc_pages(1, PAGE_SIZE)
+        .map_err(|_| DevError::NoMemory)?;
+    unsafe { (vaddr as *mut u8).write_bytes(0, PAGE_SIZE) };
+    Ok(virt_to_phys(vaddr.into()).as_usize())
+}
+
+fn free_table(paddr: usize) {
+    global_allocator().dealloc_pages(phys_to_virt(paddr.into()).as_usize(), 1);
+}
+
+fn table(paddr: usize) -> *mut u64 {
+    phys_to_virt(paddr.into()).as_mut_ptr() as *mut u64
+}
+
+impl VtD {
+    /// Sets up the unit whose registers are at `paddr` and enables
+    /// translation. No device can do DMA until it's attached to a domain.
+    pub fn new(paddr: usize) -> DevResult<Self> {
+        let regs = phys_to_virt(paddr.into()).as_usize();
+        let mut unit = Self {
+            regs,
+            iotlb: 0,
+            root: 0,
+            levels: 4,
+            coherent: true,
+        };
+        let cap = unit.read64(VTD_CAP);
+        let ecap = unit.read64(VTD_ECAP);
+        unit.iotlb = ((ecap >> 8) & 0x3ff) as usize * 16;
+        unit.coherent = ecap & 1 != 0;
+        // SAGAW: bit 2 is 4-level (48-bit), bit 1 is 3-level (39-bit).
+        unit.levels = match (cap >> 8) & 0x1f {
+            sagaw if sagaw & 0b100 != 0 => 4,
+            sagaw if sagaw & 0b010 != 0 => 3,
+            _ => {
+                warn!("VT-d: no supported address width, CAP {:#x}", cap);
+                return Err(DevError::Unsupported);
+            }
+        };
+        unit.root = alloc_table()?;
+
+        unit.write64(VTD_RTADDR, unit.root as u64);
+        unit.command(GCMD_SRTP);
+        unit.write64(VTD_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        while unit.read64(VTD_CCMD) & CCMD_ICC != 0 {}
+        unit.flush_iotlb(IOTLB_GLOBAL);
+        unit.command(GCMD_TE);
+        info!(
+            "VT-d at PA:{:#x}: {}-level tables, {}coherent",
+            paddr,
+            unit.levels,
+            if unit.coherent { "" } else { "non-" }
+        );
+        Ok(unit)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.regs + offset) as *const u32).read_volatile() }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { ((self.regs + offset) as *const u64).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { ((self.regs + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { ((self.regs + offset) as *mut u64).write_volatile(value) }
+    }
+
+    /// Sets a bit of the global command register, and waits for the unit to
+    /// acknowledge it in the status register.
+    fn command(&self, bit: u32) {
+        let status = self.read32(VTD_GSTS) & GSTS_PERSISTENT;
+        self.write32(VTD_GCMD, status | bit);
+        while self.read32(VTD_GSTS) & bit == 0 {}
+    }
+
+    fn flush_iotlb(&self, granularity: u64) {
+        let reg = self.iotlb + 8;
+        self.write64(reg, IOTLB_IVT | IOTLB_DRAIN | granularity);
+        while self.read64(reg) & IOTLB_IVT != 0 {}
+    }
+
+    /// Makes a table entry written by the CPU visible to the unit.
+    fn sync_entry(&self, entry: *mut u64) {
+        if !self.coherent {
+            #[cfg(target_arch = "x86_64")]
+            unsafe {
+                core::arch::x86_64::_mm_clflush(entry as *const u8)
+            };
+        }
+    }
+
+    fn write_entry(&self, entry: *mut u64, value: u64) {
+        unsafe { entry.write_volatile(value) };
+        self.sync_entry(entry);
+    }
+
+    /// Returns the context entry of `bus:devfn`, allocating the context
+    /// table of the bus if needed.
+    fn context_entry(&self, bus: u8, devfn: u8) -> DevResult<*mut u64> {
+        let root_entry = unsafe { table(self.root).add(bus as usize * 2) };
+        let mut ctx = unsafe { root_entry.read_volatile() };
+        if ctx & ENTRY_PRESENT == 0 {
+            ctx = alloc_table()? as u64 | ENTRY_PRESENT;
+            self.write_entry(root_entry, ctx);
+        }
+        Ok(unsafe { table((ctx & PTE_ADDR_MASK) as usize).add(devfn as usize * 2) })
+    }
+
+    /// Points the context entry of `bus:devfn` to a new, empty page table
+    /// for `domain`, and returns the table.
+    pub fn attach(&self, bus: u8, devfn: u8, domain: DomainId) -> DevResult<usize> {
+        let entry = self.context_entry(bus, devfn)?;
+        let pt = alloc_table()?;
+        let width = if self.levels == 4 { 2 } else { 1 };
+        unsafe {
+            // The high half first, the entry becomes present with the low half.
+            self.write_entry(entry.add(1), width | (domain as u64) << 8);
+            self.write_entry(entry, pt as u64 | ENTRY_PRESENT);
+        }
+        self.write64(VTD_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        while self.read64(VTD_CCMD) & CCMD_ICC != 0 {}
+        Ok(pt)
+    }
+
+    /// Clears the context entry of `bus:devfn` and frees the page table of
+    /// its domain.
+    pub fn detach(&self, bus: u8, devfn: u8, domain: DomainId, pt: usize) {
+        if let Ok(entry) = self.context_entry(bus, devfn) {
+            self.write_entry(entry, 0);
+            self.write64(VTD_CCMD, CCMD_ICC | CCMD_GLOBAL);
+            while self.read64(VTD_CCMD) & CCMD_ICC != 0 {}
+        }
+        self.flush_iotlb(IOTLB_DOMAIN | (domain as u64) << 32);
+        self.free_tables(pt, self.levels);
+    }
+
+    fn free_tables(&self, pt: usize, level: usize) {
+        if level > 1 {
+            for i in 0..ENTRIES {
+                let entry = unsafe { table(pt).add(i).read_volatile() };
+                if entry & (PTE_READ | PTE_WRITE) != 0 {
+                    self.free_tables((entry & PTE_ADDR_MASK) as usize, level - 1);
+                }
+            }
+        }
+        free_table(pt);
+    }
+
+    /// Returns the leaf entry of `iova` in the page table `pt`, allocating
+    /// intermediate tables if `alloc` is set.
+    fn leaf(&self, pt: usize, iova: DmaAddr, alloc: bool) -> DevResult<Option<*mut u64>> {
+        let mut table_paddr = pt;
+        for level in (1..self.levels).rev() {
+            let index = (iova >> (12 + 9 * level)) & (ENTRIES - 1);
+            let entry = unsafe { table(table_paddr).add(index) };
+            let mut value = unsafe { entry.read_volatile() };
+            if value & (PTE_READ | PTE_WRITE) == 0 {
+                if !alloc {
+                    return Ok(None);
+                }
+                value = alloc_table()? as u64 | PTE_READ | PTE_WRITE;
+                self.write_entry(entry, value);
+            }
+            table_paddr = (value & PTE_ADDR_MASK) as usize;
+        }
+        let index = (iova >> 12) & (ENTRIES - 1);
+        Ok(Some(unsafe { table(table_paddr).add(index) }))
+    }
+
+    /// Maps the pages `[iova, iova + size)` to `[paddr, paddr + size)`.
+    pub fn map(
+        &self,
+        pt: usize,
+        domain: DomainId,
+        iova: DmaAddr,
+        paddr: usize,
+        size: usize,
+        perm: IommuPerm,
+    ) -> DevResult {
+        let mut flags = 0;
+        if perm.read {
+            flags |= PTE_READ;
+        }
+        if perm.write {
+            flags |= PTE_WRITE;
+        }
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let entry = self.leaf(pt, iova + offset, true)?.unwrap();
+            self.write_entry(entry, (paddr + offset) as u64 | flags);
+        }
+        // Needed if the unit caches non-present entries (CAP.CM, set by QEMU).
+        self.flush_iotlb(IOTLB_DOMAIN | (domain as u64) << 32);
+        Ok(())
+    }
+
+    /// Unmaps the pages `[iova, iova + size)`.
+    pub fn unmap(&self, pt: usize, domain: DomainId, iova: DmaAddr, size: usize) {
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            if let Ok(Some(entry)) = self.leaf(pt, iova + offset, false) {
+                self.write_entry(entry, 0);
+            }
+        }
+        self.flush_iotlb(IOTLB_DOMAIN | (domain as u64) << 32);
+    }
+
+    /// Returns the physical address that `iova` is mapped to.
+    pub fn translate(&self, pt: usize, iova: DmaAddr) -> Option<usize> {
+        let entry = self.leaf(pt, iova, false).ok()??;
+        let value = unsafe { entry.read_volatile() };
+        (value & (PTE_READ | PTE_WRITE) != 0)
+            .then(|| (value & PTE_ADDR_MASK) as usize + iova % PAGE_SIZE)
+    }
+}
@@ -0,0 +1,343 @@
+//! DMA memory shared by all drivers.
+//!
+//! Drivers get DMA memory in two ways:
+//!
+//! - **Coherent**: [`alloc_coherent`] returns zeroed pages that both the CPU
+//!   and the device may access at any time, e.g. descriptor rings.
+//! - **Streaming**: [`map`] hands an existing buffer to the device for one
+//!   transfer, and [`unmap`] gives it back to the CPU, e.g. packet buffers.
+//!
+//! All of them are made for a [`DmaDevice`], the handle of the device bound
+//! when it's probed.
+//!
+//! Each device can only reach the bus addresses under its [`DmaMask`]. With
+//! the `swiotlb` feature, memory outside of it is replaced by [bounce
+//! buffers](swiotlb). Otherwise it's reported as an error rather than given
+//! to the device. With the `iommu` feature, devices are given I/O virtual
+//! addresses that only reach the memory shared with them, see [`iommu`].
+//!
+//! On platforms where DMA doesn't snoop the CPU caches, see [`cache`]. To
+//! catch leaks and misuse, enable the `dma-debug` feature, see [`debug`].
+//!
+//! The HAL adapters of the driver crates (`VirtIoHal`, `IxgbeHal` and the
+//! e1000 `KernelFunc`) are thin wrappers over this module.
+
+use core::ptr::NonNull;
+
+use axalloc::global_allocator;
+use axhal::mem::{phys_to_virt, virt_to_phys};
+
+use crate::prelude::*;
+
+pub mod cache;
+#[cfg(feature = "dma-debug")]
+pub mod debug;
+mod device;
+#[cfg(feature = "iommu")]
+pub mod iommu;
+mod pool;
+#[cfg(feature = "swiotlb")]
+pub mod swiotlb;
+
+pub use self::cache::{DmaCoherency, UncachedWindow};
+pub(crate) use self::device::{
+    release as release_device, with_dma_index, with_dma_offsets, NUM_SHARED,
+};
+pub use self::device::{DmaDevice, DmaOwner, MAX_DMA_DEVICES};
+
+/// Size of the pages that coherent allocations are made of.
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// An address in the DMA address space, as seen by devices.
+pub type DmaAddr = usize;
+
+/// The direction of a streaming DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    /// The device both reads and writes the buffer.
+    Bidirectional,
+}
+
+/// The DMA addresses a device can reach: all addresses whose bits above the
+/// mask are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaMask(u64);
+
+impl DmaMask {
+    /// A device that can reach the whole 64-bit address space.
+    pub const BITS_64: Self = Self::bits(64);
+    /// A device that can only reach the low 4 GiB.
+    pub const BITS_32: Self = Self::bits(32);
+
+    /// A device with `n`-bit DMA addressing.
+    pub const fn bits(n: u32) -> Self {
+        if n >= 64 {
+            Self(u64::MAX)
+        } else {
+            Self((1 << n) - 1)
+        }
+    }
+
+    /// Returns whether the device can reach `[addr, addr + size)`.
+    pub const fn contains(&self, addr: DmaAddr, size: usize) -> bool {
+        let last = (addr as u64).saturating_add(size.saturating_sub(1) as u64);
+        last & !self.0 == 0
+    }
+}
+
+/// A coherent DMA allocation, returned by [`alloc_coherent`].
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    /// Where the CPU accesses the region.
+    pub vaddr: NonNull<u8>,
+    /// Where the device accesses the region.
+    pub dma_addr: DmaAddr,
+    /// The size in bytes, a multiple of [`PAGE_SIZE`].
+    pub size: usize,
+}
+
+const fn pages(size: usize) -> usize {
+    size.div_ceil(PAGE_SIZE)
+}
+
+/// Converts the virtual address of memory in the kernel's linear mapping, or
+/// of a coherent allocation, to its DMA address.
+///
+/// With an [IOMMU](iommu), only memory in coherent allocations is reachable
+/// by devices, at the returned address.
+pub fn virt_to_dma(vaddr: usize) -> DmaAddr {
+    #[cfg(feature = "iommu")]
+    if let Some(iova) = iommu::coherent_to_iova(vaddr) {
+        return iova;
+    }
+    bus_addr(vaddr)
+}
+
+/// Converts a DMA address given by this module back to the virtual address.
+pub fn dma_to_virt(dma_addr: DmaAddr) -> usize {
+    #[cfg(feature = "iommu")]
+    let dma_addr = iommu::translate(dma_addr).unwrap_or(dma_addr);
+    phys_to_virt(dma_addr.into()).as_usize()
+}
+
+/// The DMA address of `vaddr` before translation by an IOMMU.
+fn bus_addr(vaddr: usize) -> DmaAddr {
+    cache::uncached_to_dma(vaddr).unwrap_or_else(|| virt_to_phys(vaddr.into()).as_usize())
+}
+
+/// The addresses that the memory given to a device with `mask` must be in.
+/// They are not restricted if the IOMMU translates them.
+fn bus_mask(mask: DmaMask) -> DmaMask {
+    #[cfg(feature = "iommu")]
+    if iommu::is_enabled() {
+        return DmaMask::BITS_64;
+    }
+    mask
+}
+
+/// Allocates at least `size` bytes of zeroed, page-aligned memory that the
+/// device `dev` can access at any time.
+///
+/// On [non-coherent](cache) platforms the memory is uncached. If the
+/// platform provides no uncached memory, [`DevError::Unsupported`] is
+/// returned.
+pub fn alloc_coherent(dev: DmaDevice, size: usize, mask: DmaMask) -> DevResult<DmaRegion> {
+    let region = alloc_coherent_inner(size, bus_mask(mask))?;
+    #[cfg(feature = "iommu")]
+    let region = match iommu::map_coherent(
+        dev,
+        region.vaddr.as_ptr() as usize,
+        region.dma_addr,
+        region.size,
+        mask,
+    ) {
+        Some(Ok(iova)) => DmaRegion {
+            dma_addr: iova,
+            ..region
+        },
+        Some(Err(e)) => {
+            unsafe { release(region) };
+            return Err(e);
+        }
+        None => region,
+    };
+    #[cfg(feature = "dma-debug")]
+    debug::track(dev, debug::DmaKind::Coherent, region.dma_addr, region.size);
+    let _ = dev;
+    Ok(region)
+}
+
+fn alloc_coherent_inner(size: usize, mask: DmaMask) -> DevResult<DmaRegion> {
+    if size == 0 {
+        return Err(DevError::InvalidParam);
+    }
+    let size = pages(size) * PAGE_SIZE;
+    if let Some(res) = cache::alloc_uncached(size, mask) {
+        let (dma_addr, vaddr) = res?;
+        unsafe { vaddr.as_ptr().write_bytes(0, size) };
+        return Ok(DmaRegion {
+            vaddr,
+            dma_addr,
+            size,
+        });
+    }
+    if cache::is_non_coherent() {
+        // Cached memory would not stay coherent with the device.
+        warn!("DMA: no uncached memory for a coherent allocation");
+        return Err(DevError::Unsupported);
+    }
+
+    let (dma_addr, vaddr) = alloc_cached(size, mask)?;
+    unsafe { vaddr.as_ptr().write_bytes(0, size) };
+    Ok(DmaRegion {
+        vaddr,
+        dma_addr,
+        size,
+    })
+}
+
+/// Allocates pages from the global allocator, or from the bounce-buffer
+/// pool if they are out of the device's reach.
+fn alloc_cached(size: usize, mask: DmaMask) -> DevResult<(DmaAddr, NonNull<u8>)> {
+    let vaddr = global_allocator()
+        .alloc_pages(pages(size), PAGE_SIZE)
+        .map_err(|_| DevError::NoMemory)?;
+    let dma_addr = bus_addr(vaddr);
+    #[cfg(feature = "swiotlb")]
+    if swiotlb::needed(dma_addr, size, mask) {
+        global_allocator().dealloc_pages(vaddr, pages(size));
+        return swiotlb::alloc_coherent(size, mask);
+    }
+    if !mask.contains(dma_addr, size) {
+        warn!(
+            "DMA: [{:#x}, {:#x}) is out of the device's reach ({:?})",
+            dma_addr,
+            dma_addr + size,
+            mask
+        );
+        global_allocator().dealloc_pages(vaddr, pages(size));
+        return Err(DevError::NoMemory);
+    }
+    Ok((
+        dma_addr,
+        NonNull::new(vaddr as *mut u8).ok_or(DevError::NoMemory)?,
+    ))
+}
+
+/// Frees a region allocated by [`alloc_coherent`].
+///
+/// # Safety
+///
+/// `region` must come from [`alloc_coherent`] for `dev` (a smaller `size` is
+/// rounded up the same way), and the device must no longer access it.
+pub unsafe fn free_coherent(dev: DmaDevice, region: DmaRegion) {
+    let size = pages(region.size) * PAGE_SIZE;
+    #[cfg(feature = "dma-debug")]
+    if !debug::untrack(dev, debug::DmaKind::Coherent, region.dma_addr, size) {
+        return;
+    }
+    #[cfg(feature = "iommu")]
+    let region = match iommu::unmap_coherent(dev, region.dma_addr) {
+        Some(dma_addr) => DmaRegion { dma_addr, ..region },
+        None => region,
+    };
+    let _ = dev;
+    release(DmaRegion { size, ..region });
+}
+
+/// Gives back the memory of a coherent allocation.
+unsafe fn release(region: DmaRegion) {
+    let size = region.size;
+    if cache::free_uncached(region.dma_addr, size) {
+        return;
+    }
+    #[cfg(feature = "swiotlb")]
+    if swiotlb::is_bounced(region.dma_addr) {
+        swiotlb::free(region.dma_addr, size);
+        return;
+    }
+    global_allocator().dealloc_pages(region.vaddr.as_ptr() as usize, pages(size));
+}
+
+/// Maps a buffer for a streaming transfer by the device `dev`, returns its
+/// DMA address.
+///
+/// Returns [`DevError::Again`] if the buffer must be bounced and all bounce
+/// buffers are in use. It can be retried after other buffers are unmapped.
+///
+/// # Safety
+///
+/// The buffer must be in the kernel's linear mapping, and the CPU must not
+/// access it until it's [`unmap`]ped.
+pub unsafe fn map(
+    dev: DmaDevice,
+    buf: NonNull<[u8]>,
+    dir: DmaDirection,
+    mask: DmaMask,
+) -> DevResult<DmaAddr> {
+    let dma_addr = bus_addr(buf.as_ptr() as *mut u8 as usize);
+    let bus_mask = bus_mask(mask);
+    #[cfg(feature = "swiotlb")]
+    let dma_addr = if swiotlb::needed(dma_addr, buf.len(), bus_mask) {
+        swiotlb::map(buf, bus_mask)?
+    } else {
+        dma_addr
+    };
+    if !bus_mask.contains(dma_addr, buf.len()) {
+        warn!(
+            "DMA: buffer at {:#x} ({} bytes) is out of the device's reach ({:?})",
+            dma_addr,
+            buf.len(),
+            mask
+        );
+        return Err(DevError::NoMemory);
+    }
+    cache::sync_for_device(phys_to_virt(dma_addr.into()).as_usize(), buf.len(), dir);
+    #[cfg(feature = "iommu")]
+    let dma_addr = match iommu::map(dev, dma_addr, buf.len(), dir, mask) {
+        Some(Ok(iova)) => iova,
+        Some(Err(e)) => {
+            #[cfg(feature = "swiotlb")]
+            if swiotlb::is_bounced(dma_addr) {
+                swiotlb::free(dma_addr, buf.len());
+            }
+            return Err(e);
+        }
+        None => dma_addr,
+    };
+    #[cfg(feature = "dma-debug")]
+    debug::track(dev, debug::DmaKind::Streaming(dir), dma_addr, buf.len());
+    let _ = dev;
+    Ok(dma_addr)
+}
+
+/// Ends a streaming transfer of a buffer mapped by [`map`], after which the
+/// CPU may access it again.
+///
+/// # Safety
+///
+/// `dev`, `dma_addr`, `buf` and `dir` must be the same as given to and
+/// returned by [`map`], and the device must no longer access the buffer.
+pub unsafe fn unmap(dev: DmaDevice, dma_addr: DmaAddr, buf: NonNull<[u8]>, dir: DmaDirection) {
+    #[cfg(feature = "dma-debug")]
+    if !debug::untrack(dev, debug::DmaKind::Streaming(dir), dma_addr, buf.len()) {
+        return;
+    }
+    #[cfg(feature = "iommu")]
+    let dma_addr = match iommu::unmap(dev, dma_addr, buf.len()) {
+        Some(dma_addr) => dma_addr,
+        // The domains it was mapped in are gone.
+        None if iommu::is_enabled() => bus_addr(buf.as_ptr() as *mut u8 as usize),
+        None => dma_addr,
+    };
+    let _ = dev;
+    cache::sync_for_cpu(phys_to_virt(dma_addr.into()).as_usize(), buf.len(), dir);
+    #[cfg(feature = "swiotlb")]
+    if swiotlb::is_bounced(dma_addr) {
+        swiotlb::unmap(dma_addr, buf, dir);
+    }
+}
@@ -0,0 +1,99 @@
+//! A fixed range of DMA memory divided into slots, used for the bounce
+//! buffers and the uncached pool.
+
+use super::DmaAddr;
+
+/// A range of `WORDS * 64` slots of `SLOT_SIZE` bytes, at most.
+pub(super) struct SlotPool<const SLOT_SIZE: usize, const WORDS: usize> {
+    /// DMA address of the first slot.
+    base: DmaAddr,
+    /// Where the CPU accesses the first slot.
+    vbase: usize,
+    num_slots: usize,
+    /// One bit for each slot, set if it's in use.
+    used: [u64; WORDS],
+}
+
+impl<const SLOT_SIZE: usize, const WORDS: usize> SlotPool<SLOT_SIZE, WORDS> {
+    /// Maximum size of the pool in bytes.
+    pub const MAX_SIZE: usize = SLOT_SIZE * WORDS * 64;
+
+    /// Creates a pool of `size` bytes, truncated to [`MAX_SIZE`](Self::MAX_SIZE).
+    pub fn new(base: DmaAddr, vbase: usize, size: usize) -> Self {
+        Self {
+            base,
+            vbase,
+            num_slots: size.min(Self::MAX_SIZE) / SLOT_SIZE,
+            used: [0; WORDS],
+        }
+    }
+
+    /// Size of the pool in bytes.
+    pub fn size(&self) -> usize {
+        self.num_slots * SLOT_SIZE
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn set_used(&mut self, slots: core::ops::Range<usize>, used: bool) {
+        for slot in slots {
+            if used {
+                self.used[slot / 64] |= 1 << (slot % 64);
+            } else {
+                self.used[slot / 64] &= !(1 << (slot % 64));
+            }
+        }
+    }
+
+    const fn slots(size: usize) -> usize {
+        if size == 0 {
+            1
+        } else {
+            size.div_ceil(SLOT_SIZE)
+        }
+    }
+
+    /// Allocates `size` bytes aligned to `align` bytes, returns the DMA
+    /// address.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<DmaAddr> {
+        let count = Self::slots(size);
+        let align = Self::slots(align);
+        let mut start = 0;
+        while start + count <= self.num_slots {
+            match (start..start + count).find(|&slot| self.is_used(slot)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    self.set_used(start..start + count, true);
+                    return Some(self.base + start * SLOT_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Gives back `size` bytes at `dma_addr` allocated by
+    /// [`alloc`](Self::alloc).
+    pub fn free(&mut self, dma_addr: DmaAddr, size: usize) {
+        let slot = (dma_addr - self.base) / SLOT_SIZE;
+        self.set_used(slot..slot + Self::slots(size), false);
+    }
+
+    /// Returns whether `dma_addr` is in the pool.
+    pub fn contains(&self, dma_addr: DmaAddr) -> bool {
+        (self.base..self.base + self.size()).contains(&dma_addr)
+    }
+
+    /// Returns the DMA address of `vaddr`, if the CPU accesses the pool there.
+    pub fn dma_addr(&self, vaddr: usize) -> Option<DmaAddr> {
+        (self.vbase..self.vbase + self.size())
+            .contains(&vaddr)
+            .then(|| self.base + (vaddr - self.vbase))
+    }
+
+    /// Returns where the CPU accesses `dma_addr` in the pool.
+    pub fn virt(&self, dma_addr: DmaAddr) -> *mut u8 {
+        (self.vbase + (dma_addr - self.base)) as *mut u8
+    }
+}
@@ -0,0 +1,150 @@
+//! Bounce buffers for devices that can't reach the memory of a buffer.
+//!
+//! A pool of device-visible memory is set up at boot by
+//! [`InitArgs::swiotlb`](crate::InitArgs::swiotlb). When a streaming buffer
+//! is out of the device's [`DmaMask`], or when bouncing is forced (e.g. in a
+//! confidential VM where only the pool is shared with the host), [`map`]
+//! hands a slot of the pool to the device instead, and the data is copied
+//! between the slot and the buffer according to the transfer direction.
+//!
+//! [`map`]: super::map
+
+use core::ptr::NonNull;
+
+use axhal::mem::phys_to_virt;
+use spin::Mutex;
+
+use super::pool::SlotPool;
+use super::{DmaAddr, DmaDirection, DmaMask, PAGE_SIZE};
+use crate::prelude::*;
+
+/// Size of the slots the pool is divided into.
+const SLOT_SIZE: usize = 0x800;
+
+/// Up to 64 MiB of slots.
+type Slots = SlotPool<SLOT_SIZE, 512>;
+
+/// The bounce-buffer pool given to [`init_drivers_with`].
+///
+/// [`init_drivers_with`]: crate::init_drivers_with
+#[derive(Debug, Clone, Copy)]
+pub struct SwiotlbArgs {
+    /// Physical address of the pool, it must be in the kernel's linear
+    /// mapping and reachable by all devices.
+    pub paddr: usize,
+    /// Size of the pool in bytes, at most 64 MiB are used.
+    pub size: usize,
+    /// Bounce all streaming buffers and coherent allocations through the
+    /// pool, not only the ones out of the device's reach.
+    pub force: bool,
+}
+
+struct Pool {
+    slots: Slots,
+    force: bool,
+}
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+/// Sets up the bounce-buffer pool.
+pub(crate) fn init(args: &SwiotlbArgs) {
+    // Keep slots of coherent allocations page-aligned.
+    let paddr = args.paddr.next_multiple_of(PAGE_SIZE);
+    let size = args.size.saturating_sub(paddr - args.paddr);
+    if size > Slots::MAX_SIZE {
+        warn!(
+            "swiotlb: pool of {:#x} bytes is too large, only {:#x} are used",
+            size,
+            Slots::MAX_SIZE
+        );
+    }
+    let slots = Slots::new(paddr, phys_to_virt(paddr.into()).as_usize(), size);
+    info!(
+        "swiotlb: bounce buffers at [PA:{:#x}, PA:{:#x}){}",
+        paddr,
+        paddr + slots.size(),
+        if args.force { ", forced" } else { "" }
+    );
+    *POOL.lock() = Some(Pool {
+        slots,
+        force: args.force,
+    });
+}
+
+/// Returns whether `[dma_addr, dma_addr + size)` must be bounced for a
+/// device with `mask`.
+pub(super) fn needed(dma_addr: DmaAddr, size: usize, mask: DmaMask) -> bool {
+    match POOL.lock().as_ref() {
+        Some(pool) => pool.force || !mask.contains(dma_addr, size),
+        None => false,
+    }
+}
+
+/// Returns whether `dma_addr` is in the pool.
+pub(super) fn is_bounced(dma_addr: DmaAddr) -> bool {
+    POOL.lock()
+        .as_ref()
+        .is_some_and(|pool| pool.slots.contains(dma_addr))
+}
+
+/// Allocates `size` bytes from the pool, aligned to `align` bytes.
+///
+/// Returns [`DevError::Again`] if the pool is full for now, but could hold
+/// the buffer once others are freed.
+fn alloc(size: usize, align: usize, mask: DmaMask) -> DevResult<DmaAddr> {
+    let mut pool = POOL.lock();
+    let pool = pool.as_mut().ok_or(DevError::NoMemory)?;
+    let dma_addr = pool.slots.alloc(size, align).ok_or_else(|| {
+        if size <= pool.slots.size() {
+            return DevError::Again;
+        }
+        warn!("swiotlb: no room for {} bytes", size);
+        DevError::NoMemory
+    })?;
+    if !mask.contains(dma_addr, size) {
+        pool.slots.free(dma_addr, size);
+        warn!(
+            "swiotlb: the pool is out of the device's reach ({:?})",
+            mask
+        );
+        return Err(DevError::NoMemory);
+    }
+    Ok(dma_addr)
+}
+
+/// Gives back `size` bytes allocated from the pool.
+pub(super) fn free(dma_addr: DmaAddr, size: usize) {
+    if let Some(pool) = POOL.lock().as_mut() {
+        pool.slots.free(dma_addr, size);
+    }
+}
+
+fn bounce_ptr(dma_addr: DmaAddr) -> *mut u8 {
+    phys_to_virt(dma_addr.into()).as_mut_ptr()
+}
+
+/// Maps `buf` to a bounce buffer, copying its content into it.
+///
+/// The content is copied even if the device only writes the buffer, so that
+/// the device never sees stale data of a previous user of the slots.
+pub(super) unsafe fn map(buf: NonNull<[u8]>, mask: DmaMask) -> DevResult<DmaAddr> {
+    let dma_addr = alloc(buf.len(), SLOT_SIZE, mask)?;
+    core::ptr::copy_nonoverlapping(buf.as_ptr() as *const u8, bounce_ptr(dma_addr), buf.len());
+    Ok(dma_addr)
+}
+
+/// Copies what the device has written back to `buf`, and frees the bounce
+/// buffer.
+pub(super) unsafe fn unmap(dma_addr: DmaAddr, buf: NonNull<[u8]>, dir: DmaDirection) {
+    if dir != DmaDirection::ToDevice {
+        core::ptr::copy_nonoverlapping(bounce_ptr(dma_addr), buf.as_ptr() as *mut u8, buf.len());
+    }
+    free(dma_addr, buf.len());
+}
+
+/// Allocates whole pages from the pool for a coherent allocation.
+pub(super) fn alloc_coherent(size: usize, mask: DmaMask) -> DevResult<(DmaAddr, NonNull<u8>)> {
+    let dma_addr = alloc(size, PAGE_SIZE, mask)?;
+    let vaddr = bounce_ptr(dma_addr);
+    Ok((dma_addr, NonNull::new(vaddr).ok_or(DevError::NoMemory)?))
+}
@@ -1,54 +1,181 @@
 //! Defines types and probe methods of all supported devices.
+//!
+//! Each driver here is enabled by its Cargo feature, which must also be
+//! listed in `DRIVER_FEATURES` of `build.rs`.
 
 #![allow(unused_imports, dead_code)]
 
 use core::ptr::NonNull;
 
-use crate::AxDeviceEnum;
-use driver_common::DeviceType;
+use crate::{AxDeviceEnum, Dependency, DeviceNaming, ProbeResult};
+use driver_common::{DevError, DeviceType};
 
 #[cfg(feature = "virtio")]
 use crate::virtio::{self, VirtIoDevMeta};
 
+#[cfg(any(feature = "ixgbe", feature = "e1000"))]
+use crate::dma::DmaDevice;
+#[cfg(any(feature = "ixgbe", feature = "e1000"))]
+use axhal::mem::phys_to_virt;
+
 #[cfg(feature = "bus-pci")]
 use driver_pci::{DeviceFunction, DeviceFunctionInfo, PciRoot};
 
+#[cfg(bus = "pci")]
+use crate::{PciDeviceId, PciIrq};
+
 pub use super::dummy::*;
 
+/// The probe methods of a driver.
+///
+/// A driver is made known to [`init_drivers`](crate::init_drivers) by
+/// registering it with [`register_driver!`](crate::register_driver).
 pub trait DriverProbe {
-    fn probe_global() -> Option<AxDeviceEnum> {
-        None
+    /// How the devices of this driver are named. If not given, the default
+    /// of the device category is used, e.g. `eth0` for NICs.
+    const NAMING: Option<DeviceNaming> = None;
+
+    /// What must be registered before this driver is probed.
+    const DEPENDS_ON: &'static [Dependency] = &[];
+
+    /// Drivers are probed in increasing priority, and drivers of the same
+    /// priority by name, so that devices are named the same on every boot
+    /// whatever order the linker puts the drivers in.
+    const PRIORITY: i32 = 0;
+
+    fn probe_global() -> ProbeResult {
+        ProbeResult::NotFound
     }
 
+    /// Stops DMA and interrupts of a device found by
+    /// [`probe_global`](Self::probe_global), before a suspend or shutdown.
+    /// Such devices are on no bus that could do it. Called once per device,
+    /// whether or not it's still in [`AllDevices`](crate::AllDevices).
+    fn quiesce_global() {}
+
+    /// Restarts a device stopped by [`quiesce_global`](Self::quiesce_global)
+    /// on resume.
+    fn resume_global() {}
+
+    /// Device tree `compatible` strings of the MMIO devices that
+    /// [`probe_mmio`](Self::probe_mmio) handles.
+    #[cfg(bus = "mmio")]
+    const MMIO_COMPATIBLE: &'static [&'static str] = &[];
+
+    /// IDs of the PCI functions that [`probe_pci`](Self::probe_pci) handles.
+    /// It's only called for functions that match an entry.
+    #[cfg(bus = "pci")]
+    const PCI_IDS: &'static [PciDeviceId] = &[];
+
+    /// Whether [`probe_pci`](Self::probe_pci) programs the device to raise
+    /// the MSI or MSI-X vectors it's given, e.g. the virtio queue vectors.
+    /// If not, the function is left on INTx, so that the device keeps its
+    /// interrupts.
+    #[cfg(bus = "pci")]
+    const MSI: bool = false;
+
     #[cfg(bus = "mmio")]
-    fn probe_mmio(_mmio_base: usize, _mmio_size: usize) -> Option<AxDeviceEnum> {
-        None
+    fn probe_mmio(_mmio_base: usize, _mmio_size: usize) -> ProbeResult {
+        ProbeResult::NotFound
     }
 
+    /// Probes a PCI function that matches [`PCI_IDS`](Self::PCI_IDS). `irq`
+    /// is the interrupt that the bus has set up for it, MSI or MSI-X vectors
+    /// if the function, the platform and the driver (see [`MSI`](Self::MSI))
+    /// support them.
     #[cfg(bus = "pci")]
     fn probe_pci(
         _root: &mut PciRoot,
         _bdf: DeviceFunction,
         _dev_info: &DeviceFunctionInfo,
-    ) -> Option<AxDeviceEnum> {
-        None
+        _irq: PciIrq,
+    ) -> ProbeResult {
+        ProbeResult::NotFound
+    }
+}
+
+/// A registered driver, the type-erased form of a [`DriverProbe`] impl.
+pub struct DriverEntry {
+    /// The driver name, e.g. `"virtio-net"`.
+    pub name: &'static str,
+    pub naming: Option<DeviceNaming>,
+    pub depends_on: &'static [Dependency],
+    pub priority: i32,
+    pub probe_global: fn() -> ProbeResult,
+    pub quiesce_global: fn(),
+    pub resume_global: fn(),
+    #[cfg(bus = "mmio")]
+    pub mmio_compatible: &'static [&'static str],
+    #[cfg(bus = "mmio")]
+    pub probe_mmio: fn(usize, usize) -> ProbeResult,
+    #[cfg(bus = "pci")]
+    pub pci_ids: &'static [PciDeviceId],
+    #[cfg(bus = "pci")]
+    pub msi: bool,
+    #[cfg(bus = "pci")]
+    pub probe_pci: fn(&mut PciRoot, DeviceFunction, &DeviceFunctionInfo, PciIrq) -> ProbeResult,
+}
+
+impl DriverEntry {
+    /// Creates the entry of driver `D`.
+    pub const fn new<D: DriverProbe>(name: &'static str) -> Self {
+        Self {
+            name,
+            naming: D::NAMING,
+            depends_on: D::DEPENDS_ON,
+            priority: D::PRIORITY,
+            probe_global: D::probe_global,
+            quiesce_global: D::quiesce_global,
+            resume_global: D::resume_global,
+            #[cfg(bus = "mmio")]
+            mmio_compatible: D::MMIO_COMPATIBLE,
+            #[cfg(bus = "mmio")]
+            probe_mmio: D::probe_mmio,
+            #[cfg(bus = "pci")]
+            pci_ids: D::PCI_IDS,
+            #[cfg(bus = "pci")]
+            msi: D::MSI,
+            #[cfg(bus = "pci")]
+            probe_pci: D::probe_pci,
+        }
     }
 }
 
+/// All registered drivers, collected by the linker in no particular order.
+/// See [`sorted_drivers`] for the order they are probed in.
+#[linkme::distributed_slice]
+pub static DRIVERS: [DriverEntry];
+
+/// Returns all registered drivers by [priority](DriverProbe::PRIORITY),
+/// then by name.
+pub(crate) fn sorted_drivers() -> impl Iterator<Item = &'static DriverEntry> {
+    let key = |i: usize| (DRIVERS[i].priority, DRIVERS[i].name, i);
+    let first = (0..DRIVERS.len()).min_by_key(|&i| key(i));
+    core::iter::successors(first, move |&prev| {
+        (0..DRIVERS.len())
+            .filter(|&i| key(i) > key(prev))
+            .min_by_key(|&i| key(i))
+    })
+    .map(|i| &DRIVERS[i])
+}
+
 #[cfg(net_dev = "virtio-net")]
 register_net_driver!(
+    <virtio::VirtIoNet as VirtIoDevMeta>::NAME,
     <virtio::VirtIoNet as VirtIoDevMeta>::Driver,
     <virtio::VirtIoNet as VirtIoDevMeta>::Device
 );
 
 #[cfg(block_dev = "virtio-blk")]
 register_block_driver!(
+    <virtio::VirtIoBlk as VirtIoDevMeta>::NAME,
     <virtio::VirtIoBlk as VirtIoDevMeta>::Driver,
     <virtio::VirtIoBlk as VirtIoDevMeta>::Device
 );
 
 #[cfg(display_dev = "virtio-gpu")]
 register_display_driver!(
+    <virtio::VirtIoGpu as VirtIoDevMeta>::NAME,
     <virtio::VirtIoGpu as VirtIoDevMeta>::Driver,
     <virtio::VirtIoGpu as VirtIoDevMeta>::Device
 );
@@ -56,13 +183,23 @@ register_display_driver!(
 cfg_if::cfg_if! {
     if #[cfg(block_dev = "ramdisk")] {
         pub struct RamDiskDriver;
-        register_block_driver!(RamDiskDriver, driver_block::ramdisk::RamDisk);
+        register_block_driver!("ramdisk", RamDiskDriver, driver_block::ramdisk::RamDisk);
 
         impl DriverProbe for RamDiskDriver {
-            fn probe_global() -> Option<AxDeviceEnum> {
+            const NAMING: Option<DeviceNaming> = Some(DeviceNaming::Numbered("ram"));
+
+            fn probe_global() -> ProbeResult {
+                const DEFAULT_SIZE: usize = 0x100_0000; // 16 MiB
+                let size = match crate::driver_param("ramdisk", "size") {
+                    Some(s) => crate::cmdline::parse_size(s).unwrap_or_else(|| {
+                        warn!("ramdisk: invalid size {:?}", s);
+                        DEFAULT_SIZE
+                    }),
+                    None => DEFAULT_SIZE,
+                };
                 // TODO: format RAM disk
-                Some(AxDeviceEnum::from_block(
-                    driver_block::ramdisk::RamDisk::new(0x100_0000), // 16 MiB
+                ProbeResult::Device(AxDeviceEnum::from_block_requests(
+                    driver_block::ramdisk::RamDisk::new(size),
                 ))
             }
         }
@@ -72,12 +209,14 @@ cfg_if::cfg_if! {
 cfg_if::cfg_if! {
     if #[cfg(block_dev = "bcm2835-sdhci")]{
         pub struct BcmSdhciDriver;
-        register_block_driver!(MmckDriver, driver_block::bcm2835sdhci::SDHCIDriver);
+        register_block_driver!("bcm2835-sdhci", BcmSdhciDriver, driver_block::bcm2835sdhci::SDHCIDriver);
 
         impl DriverProbe for BcmSdhciDriver {
-            fn probe_global() -> Option<AxDeviceEnum> {
+            const NAMING: Option<DeviceNaming> = Some(DeviceNaming::Numbered("mmcblk"));
+
+            fn probe_global() -> ProbeResult {
                 debug!("mmc probe");
-                driver_block::bcm2835sdhci::SDHCIDriver::try_new().ok().map(AxDeviceEnum::from_block)
+                driver_block::bcm2835sdhci::SDHCIDriver::try_new().ok().map(AxDeviceEnum::from_block_requests).into()
             }
         }
     }
@@ -86,46 +225,89 @@ cfg_if::cfg_if! {
 cfg_if::cfg_if! {
     if #[cfg(net_dev = "ixgbe")] {
         use crate::ixgbe::IxgbeHalImpl;
-        use axhal::mem::phys_to_virt;
         pub struct IxgbeDriver;
-        register_net_driver!(IxgbeDriver, driver_net::ixgbe::IxgbeNic<IxgbeHalImpl, 1024, 1>);
+        register_net_driver!("ixgbe", IxgbeDriver, driver_net::ixgbe::IxgbeNic<IxgbeHalImpl<{ DmaDevice::NET.index() }>, 1024, 1>);
         impl DriverProbe for IxgbeDriver {
+            // Intel 10Gb Network
+            #[cfg(bus = "pci")]
+            const PCI_IDS: &'static [PciDeviceId] = &[
+                PciDeviceId::new(driver_net::ixgbe::INTEL_VEND, driver_net::ixgbe::INTEL_82599),
+            ];
+
             #[cfg(bus = "pci")]
             fn probe_pci(
                     root: &mut driver_pci::PciRoot,
                     bdf: driver_pci::DeviceFunction,
-                    dev_info: &driver_pci::DeviceFunctionInfo,
-                ) -> Option<crate::AxDeviceEnum> {
-                    use driver_net::ixgbe::{INTEL_82599, INTEL_VEND, IxgbeNic};
-                    if dev_info.vendor_id == INTEL_VEND && dev_info.device_id == INTEL_82599 {
-                        // Intel 10Gb Network
-                        info!("ixgbe PCI device found at {:?}", bdf);
-
-                        // Initialize the device
-                        // These can be changed according to the requirments specified in the ixgbe init function.
-                        const QN: u16 = 1;
-                        const QS: usize = 1024;
-                        let bar_info = root.bar_info(bdf, 0).unwrap();
-                        match bar_info {
-                            driver_pci::BarInfo::Memory {
-                                address,
-                                size,
-                                ..
-                            } => {
-                                let ixgbe_nic = IxgbeNic::<IxgbeHalImpl, QS, QN>::init(
-                                    phys_to_virt((address as usize).into()).into(),
-                                    size as usize
-                                )
-                                .expect("failed to initialize ixgbe device");
-                                return Some(AxDeviceEnum::from_net(ixgbe_nic));
-                            }
-                            driver_pci::BarInfo::IO { .. } => {
-                                error!("ixgbe: BAR0 is of I/O type");
-                                return None;
+                    _dev_info: &driver_pci::DeviceFunctionInfo,
+                    irq: crate::PciIrq,
+                ) -> ProbeResult {
+                    use driver_net::ixgbe::IxgbeNic;
+                    info!("ixgbe PCI device found at {:?}, irq {:?}", bdf, irq);
+
+                    // Initialize the device
+                    // These can be changed according to the requirments specified in the ixgbe init function.
+                    const QN: u16 = 1;
+                    const QS: usize = 1024;
+                    let queue_size = match crate::driver_param("ixgbe", "queue_size") {
+                        Some(s) => s.parse().unwrap_or_else(|_| {
+                            warn!("ixgbe: invalid queue size {:?}", s);
+                            QS
+                        }),
+                        None => QS,
+                    };
+                    let bar_info = root.bar_info(bdf, 0).unwrap();
+                    match bar_info {
+                        driver_pci::BarInfo::Memory {
+                            address,
+                            size,
+                            ..
+                        } => {
+                            let base = phys_to_virt((address as usize).into()).into();
+                            let size = size as usize;
+                            let location = crate::bus::pci_location(bdf);
+                            let dma_dev = match DmaDevice::bind_for(DeviceType::Net, "ixgbe", location) {
+                                Ok(dma_dev) => dma_dev,
+                                Err(e) => return ProbeResult::Error(e),
+                            };
+                            // The queue size and the DMA handle are type
+                            // parameters, so only the dynamic device model
+                            // can pick them at boot time.
+                            #[cfg(net_dyn)]
+                            let nic = crate::dma::with_dma_index!(dma_dev, DMA => match queue_size {
+                                256 => IxgbeNic::<IxgbeHalImpl<DMA>, 256, QN>::init(base, size)
+                                    .map(AxDeviceEnum::from_net),
+                                512 => IxgbeNic::<IxgbeHalImpl<DMA>, 512, QN>::init(base, size)
+                                    .map(AxDeviceEnum::from_net),
+                                _ => {
+                                    if queue_size != QS {
+                                        warn!("ixgbe: unsupported queue size {}, using {}", queue_size, QS);
+                                    }
+                                    IxgbeNic::<IxgbeHalImpl<DMA>, QS, QN>::init(base, size)
+                                        .map(AxDeviceEnum::from_net)
+                                }
+                            });
+                            #[cfg(not(net_dyn))]
+                            let nic = {
+                                debug_assert_eq!(dma_dev, DmaDevice::NET);
+                                if queue_size != QS {
+                                    warn!("ixgbe: queue size is fixed to {} in the static device model", QS);
+                                }
+                                IxgbeNic::<IxgbeHalImpl<{ DmaDevice::NET.index() }>, QS, QN>::init(base, size)
+                                    .map(AxDeviceEnum::from_net)
+                            };
+                            match nic {
+                                Ok(nic) => ProbeResult::Device(nic),
+                                Err(e) => {
+                                    error!("ixgbe: failed to initialize the device: {:?}", e);
+                                    ProbeResult::Error(e)
+                                }
                             }
                         }
+                        driver_pci::BarInfo::IO { .. } => {
+                            error!("ixgbe: BAR0 is of I/O type");
+                            ProbeResult::Error(DevError::Unsupported)
+                        }
                     }
-                    None
             }
         }
     }
@@ -133,71 +315,99 @@ cfg_if::cfg_if! {
 
 cfg_if::cfg_if! {
     if #[cfg(net_dev = "e1000")] {
-        use axalloc::global_allocator;
-        use axhal::mem::{phys_to_virt, virt_to_phys};
         use driver_net::e1000::{E1000Nic, KernelFunc};
-        use core::alloc::Layout;
+        use crate::dma::{self, DmaMask, DmaRegion};
+
+        pub struct KernelFuncObj {
+            /// The handle the NIC does its DMA for.
+            dma_dev: DmaDevice,
+        }
+
+        impl KernelFuncObj {
+            /// The e1000 family supports 64-bit DMA addressing.
+            const DMA_MASK: DmaMask = DmaMask::BITS_64;
+        }
 
-        pub struct KernelFuncObj;
         impl KernelFunc for KernelFuncObj {
             /// Allocate consequent physical memory for DMA;
             /// Return (cpu virtual address, dma physical address) which is page aligned.
-            //fn dma_alloc_coherent(pages: usize) -> usize;
             fn dma_alloc_coherent(&mut self, pages: usize) -> (usize, usize) {
-            let vaddr = if let Ok(start_vaddr) = global_allocator().alloc_pages(pages, Self::PAGE_SIZE) {
-                        start_vaddr
-                } else {
-                    error!("failed to alloc pages");
-                    return (0, 0);
-                };
-                let paddr = virt_to_phys((vaddr).into());
-                info!("dma_alloc_coherent pages @ vaddr={:#x}, paddr={:#x}", vaddr, paddr);
-                (vaddr, paddr.as_usize())
+                match dma::alloc_coherent(self.dma_dev, pages * Self::PAGE_SIZE, Self::DMA_MASK) {
+                    Ok(region) => (region.vaddr.as_ptr() as usize, region.dma_addr),
+                    Err(e) => {
+                        error!("e1000: failed to allocate {} DMA pages: {:?}", pages, e);
+                        (0, 0)
+                    }
+                }
             }
 
             /// Deallocate DMA memory by virtual address
             fn dma_free_coherent(&mut self, vaddr: usize, pages: usize) {
-                global_allocator().dealloc_pages(vaddr, pages);
+                let Some(ptr) = NonNull::new(vaddr as *mut u8) else {
+                    return;
+                };
+                unsafe {
+                    dma::free_coherent(
+                        self.dma_dev,
+                        DmaRegion {
+                            vaddr: ptr,
+                            dma_addr: dma::virt_to_dma(vaddr),
+                            size: pages * Self::PAGE_SIZE,
+                        },
+                    )
+                };
             }
         }
 
         pub struct E1000Driver;
-        register_net_driver!(E1000Driver, driver_net::e1000::E1000Nic<'static, KernelFuncObj>);
+        register_net_driver!("e1000", E1000Driver, driver_net::e1000::E1000Nic<'static, KernelFuncObj>);
 
 
         impl DriverProbe for E1000Driver {
+            #[cfg(bus = "pci")]
+            const PCI_IDS: &'static [PciDeviceId] = &[
+                PciDeviceId::new(0x8086, 0x100e), // 82540EM
+                PciDeviceId::new(0x8086, 0x100f), // 82545EM
+            ];
+
+            #[cfg(bus = "pci")]
             fn probe_pci(
                     root: &mut driver_pci::PciRoot,
                     bdf: driver_pci::DeviceFunction,
-                    dev_info: &driver_pci::DeviceFunctionInfo,
-                ) -> Option<crate::AxDeviceEnum> {
-                    const E1000_VENDOR_ID: u16 = 0x8086;
-                    const E1000_DEVICE_ID: u16 = 0x100e;
-                    info!("PCI vendor:device = {:#x}:{:#x}", dev_info.vendor_id, dev_info.device_id);
-                    if dev_info.vendor_id == E1000_VENDOR_ID && dev_info.device_id == E1000_DEVICE_ID {
-                        info!("E1000 PCI device found at {:?}", bdf);
-
-                        // Initialize the device
-                        match root.bar_info(bdf, 0).unwrap() {
-                            driver_pci::BarInfo::Memory {
-                                address,
-                                ..
-                            } => {
-                                let kfn = KernelFuncObj;
-                                let nic = E1000Nic::<KernelFuncObj>::init(
-                                    kfn,
-                                    phys_to_virt((address as usize).into()).into()
-                                )
-                                .expect("failed to initialize e1000 device");
-                                return Some(AxDeviceEnum::from_net(nic));
-                            }
-                            driver_pci::BarInfo::IO { .. } => {
-                                error!("e1000: BAR0 is of I/O type");
-                                return None;
+                    _dev_info: &driver_pci::DeviceFunctionInfo,
+                    irq: crate::PciIrq,
+                ) -> ProbeResult {
+                    info!("E1000 PCI device found at {:?}, irq {:?}", bdf, irq);
+
+                    // Initialize the device
+                    match root.bar_info(bdf, 0).unwrap() {
+                        driver_pci::BarInfo::Memory {
+                            address,
+                            ..
+                        } => {
+                            let location = crate::bus::pci_location(bdf);
+                            // The handle is kept in `KernelFuncObj`, so each NIC gets its own.
+                            let kfn = match DmaDevice::bind("e1000", location) {
+                                Ok(dma_dev) => KernelFuncObj { dma_dev },
+                                Err(e) => return ProbeResult::Error(e),
+                            };
+                            let nic = E1000Nic::<KernelFuncObj>::init(
+                                kfn,
+                                phys_to_virt((address as usize).into()).into()
+                            );
+                            match nic {
+                                Ok(nic) => ProbeResult::Device(AxDeviceEnum::from_net(nic)),
+                                Err(e) => {
+                                    error!("e1000: failed to initialize the device: {:?}", e);
+                                    ProbeResult::Error(e)
+                                }
                             }
                         }
+                        driver_pci::BarInfo::IO { .. } => {
+                            error!("e1000: BAR0 is of I/O type");
+                            ProbeResult::Error(DevError::Unsupported)
+                        }
                     }
-                    None
             }
         }
     }
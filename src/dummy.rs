@@ -12,7 +12,9 @@ cfg_if! {
 
         pub struct DummyNetDev;
         pub struct DummyNetDrvier;
-        register_net_driver!(DummyNetDriver, DummyNetDev);
+        /// The unified type of the NIC devices.
+        #[cfg(not(net_dyn))]
+        pub type AxNetDevice = DummyNetDev;
 
         impl BaseDriverOps for DummyNetDev {
             fn device_type(&self) -> DeviceType { DeviceType::Net }
@@ -38,7 +40,9 @@ cfg_if! {
     if #[cfg(block_dev = "dummy")] {
         pub struct DummyBlockDev;
         pub struct DummyBlockDriver;
-        register_block_driver!(DummyBlockDriver, DummyBlockDev);
+        /// The unified type of the block storage devices.
+        #[cfg(not(block_dyn))]
+        pub type AxBlockDevice = DummyBlockDev;
 
         impl BaseDriverOps for DummyBlockDev {
             fn device_type(&self) -> DeviceType {
@@ -66,6 +70,21 @@ cfg_if! {
                 Err(DevError::Unsupported)
             }
         }
+
+        impl BlockRequestOps for DummyBlockDev {
+            fn read_blocks(&mut self, _: u64, _: &mut [u8]) -> DevResult {
+                Err(DevError::Unsupported)
+            }
+            fn write_blocks(&mut self, _: u64, _: &[u8]) -> DevResult {
+                Err(DevError::Unsupported)
+            }
+            fn read_blocks_sg(&mut self, _: u64, _: &mut [&mut [u8]]) -> DevResult {
+                Err(DevError::Unsupported)
+            }
+            fn write_blocks_sg(&mut self, _: u64, _: &[&[u8]]) -> DevResult {
+                Err(DevError::Unsupported)
+            }
+        }
     }
 }
 
@@ -73,7 +92,9 @@ cfg_if! {
     if #[cfg(display_dev = "dummy")] {
         pub struct DummyDisplayDev;
         pub struct DummyDisplayDriver;
-        register_display_driver!(DummyDisplayDriver, DummyDisplayDev);
+        /// The unified type of the graphics display devices.
+        #[cfg(not(display_dyn))]
+        pub type AxDisplayDevice = DummyDisplayDev;
 
         impl BaseDriverOps for DummyDisplayDev {
             fn device_type(&self) -> DeviceType {
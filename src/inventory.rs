@@ -0,0 +1,175 @@
+//! A record of every device found on the buses, whether a driver has bound
+//! it or not.
+//!
+//! Each PCI function and MMIO region scanned during probing gets one
+//! [`ScannedDevice`] entry, which is updated as drivers bind, defer or fail
+//! on it. The table is returned by [`AllDevices::inventory`].
+
+use core::fmt;
+
+use crate::{prelude::*, AllDevices, DeviceIrq, DeviceLocation};
+
+#[cfg(bus = "pci")]
+use arrayvec::ArrayVec;
+#[cfg(bus = "pci")]
+use driver_pci::{BarInfo, DeviceFunctionInfo};
+
+cfg_if::cfg_if! {
+    if #[cfg(any(net_dyn, block_dyn, display_dyn))] {
+        type Storage = alloc::vec::Vec<ScannedDevice>;
+    } else {
+        /// Maximum number of entries in the inventory, if the heap is not used.
+        const MAX_SCANNED: usize = 64;
+
+        type Storage = arrayvec::ArrayVec<ScannedDevice, MAX_SCANNED>;
+    }
+}
+
+/// What became of a scanned device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanStatus {
+    /// The named driver has registered it.
+    Bound(&'static str),
+    /// The named driver has deferred it, and it was still waiting when
+    /// probing ended.
+    Deferred(&'static str),
+    /// No driver handles it, or all drivers that match it failed.
+    NoDriver,
+    /// It was bound, then unbound by [`AllDevices::unbind`].
+    Unbound,
+    /// A PCI-to-PCI bridge, handled by the bus itself.
+    Bridge,
+}
+
+/// An error while setting up or probing a device.
+#[derive(Debug)]
+pub struct ProbeError {
+    /// The driver that returned the error, or `None` if the bus failed to
+    /// set up the device.
+    pub driver: Option<&'static str>,
+    pub error: DevError,
+}
+
+/// An entry of the inventory: a PCI function or MMIO region that was scanned.
+#[derive(Debug)]
+pub struct ScannedDevice {
+    /// Where the device is attached.
+    pub location: DeviceLocation,
+    /// Vendor, device and class IDs of a PCI function.
+    #[cfg(bus = "pci")]
+    pub pci_info: Option<DeviceFunctionInfo>,
+    /// BARs of a PCI function assigned during the scan, with their indices.
+    #[cfg(bus = "pci")]
+    pub bars: ArrayVec<(u8, BarInfo), 6>,
+    /// The first `compatible` string of the device tree node.
+    #[cfg(bus = "mmio")]
+    pub compatible: Option<&'static str>,
+    /// The interrupt of the device, if it has one.
+    pub irq: Option<DeviceIrq>,
+    pub status: ScanStatus,
+    /// The last error while setting up or probing the device.
+    pub error: Option<ProbeError>,
+}
+
+impl ScannedDevice {
+    pub(crate) fn new(location: DeviceLocation) -> Self {
+        Self {
+            location,
+            #[cfg(bus = "pci")]
+            pci_info: None,
+            #[cfg(bus = "pci")]
+            bars: ArrayVec::new(),
+            #[cfg(bus = "mmio")]
+            compatible: None,
+            irq: None,
+            status: ScanStatus::NoDriver,
+            error: None,
+        }
+    }
+}
+
+/// Formats the entry as one `lspci`-style line, e.g.
+/// `00:02.0 8086:100e (...) irq Line(11): e1000`.
+impl fmt::Display for ScannedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.location)?;
+        #[cfg(bus = "pci")]
+        if let Some(info) = &self.pci_info {
+            write!(f, " {}", info)?;
+        }
+        #[cfg(bus = "mmio")]
+        if let Some(compatible) = self.compatible {
+            write!(f, " {:?}", compatible)?;
+        }
+        if let Some(irq) = self.irq {
+            write!(f, " irq {:?}", irq)?;
+        }
+        match self.status {
+            ScanStatus::Bound(driver) => write!(f, ": {}", driver)?,
+            ScanStatus::Deferred(driver) => write!(f, ": {} (deferred)", driver)?,
+            ScanStatus::NoDriver => write!(f, ": no driver")?,
+            ScanStatus::Unbound => write!(f, ": unbound")?,
+            ScanStatus::Bridge => write!(f, ": bridge")?,
+        }
+        if let Some(e) = &self.error {
+            write!(f, " [{}: {:?}]", e.driver.unwrap_or("bus"), e.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// All scanned devices, in scan order.
+#[derive(Default)]
+pub(crate) struct Inventory(Storage);
+
+impl Inventory {
+    pub(crate) fn push(&mut self, dev: ScannedDevice) {
+        #[cfg(not(any(net_dyn, block_dyn, display_dyn)))]
+        if self.0.is_full() {
+            warn!(
+                "too many scanned devices (max {}), {} is not recorded",
+                MAX_SCANNED, dev.location
+            );
+            return;
+        }
+        self.0.push(dev);
+    }
+
+    fn get_mut(&mut self, location: &DeviceLocation) -> Option<&mut ScannedDevice> {
+        self.0.iter_mut().find(|dev| dev.location == *location)
+    }
+
+    /// Sets the status of the device at `location`, if it was scanned.
+    pub(crate) fn set_status(&mut self, location: &DeviceLocation, status: ScanStatus) {
+        if let Some(dev) = self.get_mut(location) {
+            dev.status = status;
+        }
+    }
+
+    /// Records an error of the device at `location`, if it was scanned.
+    pub(crate) fn set_error(
+        &mut self,
+        location: &DeviceLocation,
+        driver: Option<&'static str>,
+        error: DevError,
+    ) {
+        if let Some(dev) = self.get_mut(location) {
+            dev.error = Some(ProbeError { driver, error });
+        }
+    }
+}
+
+impl AllDevices {
+    /// Returns all PCI functions and MMIO regions scanned while probing, in
+    /// scan order, including the ones no driver has bound.
+    pub fn inventory(&self) -> &[ScannedDevice] {
+        &self.inventory.0
+    }
+
+    /// Returns the inventory entry of the device at `location`.
+    pub fn scanned_at(&self, location: &DeviceLocation) -> Option<&ScannedDevice> {
+        self.inventory()
+            .iter()
+            .find(|dev| dev.location == *location)
+    }
+}
@@ -0,0 +1,200 @@
+//! Dispatching device interrupts to registered handlers.
+//!
+//! The interrupt of each device is recorded in its [`AxDeviceInfo`] when the
+//! device is registered. Whoever takes the device out of [`AllDevices`] can
+//! then call [`register_irq_handler`] with it, and have the platform interrupt
+//! handler forward every IRQ to [`handle_irq`].
+//!
+//! [`AxDeviceInfo`]: crate::AxDeviceInfo
+//! [`AllDevices`]: crate::AllDevices
+
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use driver_common::{DevError, DevResult};
+
+/// Number of IRQs that can have handlers, `0..MAX_IRQS`.
+///
+/// It's set by the `AXDRIVER_MAX_IRQS` environment variable at build time,
+/// and defaults to 256.
+pub const MAX_IRQS: usize = crate::structs::parse_usize(env!("AXDRIVER_MAX_IRQS"));
+/// Maximum number of handlers sharing one IRQ, e.g. a PCI INTx line.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Handles the interrupts of a device, e.g. a wrapper around the device that
+/// wakes up the tasks waiting for it.
+pub trait IrqHandler: Sync {
+    /// Called with the IRQ number that fired, in interrupt context.
+    fn handle(&self, irq: usize);
+}
+
+/// The words of a `&'static dyn IrqHandler`.
+type RawHandler = [usize; 2];
+
+/// A registered handler.
+///
+/// The handler is two words, published under a sequence lock so that a
+/// reader never calls half of a handler that is being replaced.
+struct Slot {
+    /// Odd while the slot is being written, bumped again when done.
+    seq: AtomicUsize,
+    /// The words of the handler, all zero for a free slot.
+    handler: [AtomicUsize; 2],
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            handler: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Returns the handler if the slot is in use and not being written. It
+    /// never waits, as it runs in interrupt context.
+    fn get(&self) -> Option<&'static dyn IrqHandler> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq & 1 != 0 {
+            return None;
+        }
+        let raw: RawHandler = [
+            self.handler[0].load(Ordering::Relaxed),
+            self.handler[1].load(Ordering::Relaxed),
+        ];
+        fence(Ordering::Acquire);
+        if self.seq.load(Ordering::Relaxed) != seq || raw == [0; 2] {
+            return None;
+        }
+        // SAFETY: the words were written together from a handler, as the
+        // sequence didn't change while they were read.
+        Some(unsafe { core::mem::transmute::<RawHandler, &'static dyn IrqHandler>(raw) })
+    }
+
+    /// Runs `f` with the current handler, `None` if the slot is free, and
+    /// sets the handler to what it returns.
+    fn update(
+        &self,
+        f: impl FnOnce(Option<&'static dyn IrqHandler>) -> Option<&'static dyn IrqHandler>,
+    ) {
+        let seq = loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0
+                && self
+                    .seq
+                    .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break seq;
+            }
+            core::hint::spin_loop();
+        };
+        fence(Ordering::Release);
+        let old: RawHandler = [
+            self.handler[0].load(Ordering::Relaxed),
+            self.handler[1].load(Ordering::Relaxed),
+        ];
+        // SAFETY: the words were written from a handler, and nobody else
+        // writes them while the sequence is odd.
+        let old = (old != [0; 2])
+            .then(|| unsafe { core::mem::transmute::<RawHandler, &'static dyn IrqHandler>(old) });
+        let new = f(old).map_or([0; 2], |handler| unsafe {
+            core::mem::transmute::<&'static dyn IrqHandler, RawHandler>(handler)
+        });
+        self.handler[0].store(new[0], Ordering::Relaxed);
+        self.handler[1].store(new[1], Ordering::Relaxed);
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    fn register(&self, handler: &'static dyn IrqHandler) -> bool {
+        let mut registered = false;
+        self.update(|old| {
+            registered = old.is_none();
+            old.or(Some(handler))
+        });
+        registered
+    }
+
+    fn unregister(&self, handler: &'static dyn IrqHandler) -> bool {
+        let mut unregistered = false;
+        self.update(|old| {
+            unregistered = old.is_some_and(|old| core::ptr::addr_eq(old, handler));
+            old.filter(|_| !unregistered)
+        });
+        unregistered
+    }
+}
+
+static HANDLERS: [[Slot; MAX_SHARED_HANDLERS]; MAX_IRQS] =
+    [const { [const { Slot::new() }; MAX_SHARED_HANDLERS] }; MAX_IRQS];
+
+/// The interrupt source of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceIrq {
+    /// A wired interrupt: the device tree `interrupts` property, or a PCI
+    /// INTx pin routed to the interrupt controller.
+    Line(usize),
+    /// Message signaled interrupts (MSI or MSI-X), raising IRQs
+    /// `base..base + count`.
+    Msi { base: usize, count: usize },
+}
+
+impl DeviceIrq {
+    /// Returns all IRQ numbers the device may raise.
+    pub fn irqs(&self) -> core::ops::Range<usize> {
+        match *self {
+            Self::Line(irq) => irq..irq + 1,
+            Self::Msi { base, count } => base..base + count,
+        }
+    }
+}
+
+/// Registers `handler` for every IRQ of a device, e.g. a `static` that
+/// serves the [`AxNetDevice`] or [`AxBlockDevice`] the interrupt is for.
+///
+/// A wired IRQ may be shared by several devices, all of their handlers are
+/// called when it fires.
+///
+/// [`AxNetDevice`]: crate::AxNetDevice
+/// [`AxBlockDevice`]: crate::AxBlockDevice
+pub fn register_irq_handler(irq: DeviceIrq, handler: &'static dyn IrqHandler) -> DevResult {
+    if irq.irqs().end > MAX_IRQS {
+        return Err(DevError::InvalidParam);
+    }
+    for (i, num) in irq.irqs().enumerate() {
+        let registered = HANDLERS[num].iter().any(|slot| slot.register(handler));
+        if !registered {
+            for num in irq.irqs().take(i) {
+                unregister_one(num, handler);
+            }
+            return Err(DevError::ResourceBusy);
+        }
+    }
+    Ok(())
+}
+
+/// Removes a handler added by [`register_irq_handler`].
+///
+/// The handler may still be running on another CPU when this returns.
+pub fn unregister_irq_handler(irq: DeviceIrq, handler: &'static dyn IrqHandler) {
+    for num in irq.irqs().filter(|&num| num < MAX_IRQS) {
+        unregister_one(num, handler);
+    }
+}
+
+fn unregister_one(irq: usize, handler: &'static dyn IrqHandler) {
+    HANDLERS[irq].iter().any(|slot| slot.unregister(handler));
+}
+
+/// Calls all handlers registered for `irq`, returns whether there was any.
+///
+/// This should be called by the platform interrupt handler.
+pub fn handle_irq(irq: usize) -> bool {
+    let Some(slots) = HANDLERS.get(irq) else {
+        return false;
+    };
+    let mut handled = false;
+    for handler in slots.iter().filter_map(Slot::get) {
+        handler.handle(irq);
+        handled = true;
+    }
+    handled
+}
@@ -1,25 +1,43 @@
-use axalloc::global_allocator;
-use axhal::mem::{phys_to_virt, virt_to_phys};
-use core::{alloc::Layout, ptr::NonNull};
+use axhal::mem::phys_to_virt;
+use core::ptr::NonNull;
 use driver_net::ixgbe::{IxgbeHal, PhysAddr as IxgbePhysAddr};
 
-pub struct IxgbeHalImpl;
+use crate::dma::{self, DmaDevice, DmaMask, DmaRegion};
 
-unsafe impl IxgbeHal for IxgbeHalImpl {
+/// The HAL of the ixgbe NICs doing their DMA for the [`DmaDevice`] at index
+/// `DMA`.
+pub struct IxgbeHalImpl<const DMA: usize>;
+
+impl<const DMA: usize> IxgbeHalImpl<DMA> {
+    const DEV: DmaDevice = DmaDevice::from_index(DMA);
+}
+
+/// The 82599 supports 64-bit DMA addressing.
+const DMA_MASK: DmaMask = DmaMask::BITS_64;
+
+unsafe impl<const DMA: usize> IxgbeHal for IxgbeHalImpl<DMA> {
     fn dma_alloc(size: usize) -> (IxgbePhysAddr, NonNull<u8>) {
-        let layout = Layout::from_size_align(size, 8).unwrap();
-        let vaddr = if let Ok(vaddr) = global_allocator().alloc(layout) {
-            vaddr
-        } else {
-            return (0, NonNull::dangling());
-        };
-        let paddr = virt_to_phys((vaddr.as_ptr() as usize).into());
-        (paddr.as_usize(), vaddr)
+        match dma::alloc_coherent(Self::DEV, size, DMA_MASK) {
+            Ok(region) => (region.dma_addr, region.vaddr),
+            Err(e) => {
+                error!(
+                    "ixgbe: failed to allocate {} bytes of DMA memory: {:?}",
+                    size, e
+                );
+                (0, NonNull::dangling())
+            }
+        }
     }
 
-    unsafe fn dma_dealloc(_paddr: IxgbePhysAddr, vaddr: NonNull<u8>, size: usize) -> i32 {
-        let layout = Layout::from_size_align(size, 8).unwrap();
-        global_allocator().dealloc(vaddr, layout);
+    unsafe fn dma_dealloc(paddr: IxgbePhysAddr, vaddr: NonNull<u8>, size: usize) -> i32 {
+        dma::free_coherent(
+            Self::DEV,
+            DmaRegion {
+                vaddr,
+                dma_addr: paddr,
+                size,
+            },
+        );
         0
     }
 
@@ -28,7 +46,7 @@ unsafe impl IxgbeHal for IxgbeHalImpl {
     }
 
     unsafe fn mmio_virt_to_phys(vaddr: NonNull<u8>, _size: usize) -> IxgbePhysAddr {
-        virt_to_phys((vaddr.as_ptr() as usize).into()).into()
+        dma::virt_to_dma(vaddr.as_ptr() as usize)
     }
 
     fn wait_until(duration: core::time::Duration) -> Result<(), &'static str> {
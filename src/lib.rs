@@ -13,13 +13,15 @@
 //!
 //! # Concepts
 //!
-//! This crate supports two device models depending on the `dyn` feature:
+//! This crate supports two device models, chosen for each device category by
+//! the `net-dyn`, `block-dyn` and `display-dyn` features (or `dyn` for all):
 //!
 //! - **Static**: The type of all devices is static, it is determined at compile
 //!  time by corresponding cargo features. For example, [`AxNetDevice`] will be
 //! an alias of [`VirtioNetDev`] if the `virtio-net` feature is enabled. This
 //! model provides the best performance as it avoids dynamic dispatch. But on
-//! limitation, only one device instance is supported for each device category.
+//! limitation, at most `AXDRIVER_MAX_DEVICES` (4 by default) instances are
+//! supported for each device category.
 //! - **Dynamic**: All device instance is using [trait objects] and wrapped in a
 //! `Box<dyn Trait>`. For example, [`AxNetDevice`] will be [`Box<dyn NetDriverOps>`].
 //! When call a method provided by the device, it uses [dynamic dispatch][dyn]
@@ -37,10 +39,14 @@
 //!
 //! # Other Cargo Features
 //!
-//! - `dyn`: use the dynamic device model (see above).
+//! - `dyn`: use the dynamic device model (see above). `net-dyn`, `block-dyn`
+//!    and `display-dyn` use it for one category only.
 //! - `bus-mmio`: use device tree to probe all MMIO devices.
 //! - `bus-pci`: use PCI bus to probe all PCI devices. This feature is
 //!    enabeld by default.
+//! - `dma`, `dma-debug`, `swiotlb`, `iommu`: DMA memory for drivers, see [`dma`].
+//! - `partition`, `block-cache`: partitions and a cache on top of block
+//!    devices. Both enable `block-dyn`.
 //! - `virtio`: use VirtIO devices. This is enabled if any of `virtio-blk`,
 //!   `virtio-net` or `virtio-gpu` is enabled.
 //! - `net`: use network devices. This is enabled if any feature of network
@@ -54,22 +60,36 @@
 //! [trait objects]: https://doc.rust-lang.org/book/ch17-02-trait-objects.html
 //! [dyn]: https://doc.rust-lang.org/std/keyword.dyn.html
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(doc_auto_cfg)]
 #![feature(associated_type_defaults)]
 
 #[macro_use]
 extern crate log;
 
-#[cfg(feature = "dyn")]
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
 extern crate alloc;
 
 #[macro_use]
 mod macros;
 
+#[doc(hidden)]
+pub use linkme as __linkme;
+
+#[cfg(feature = "block")]
+mod block;
+#[cfg(feature = "block-cache")]
+mod block_cache;
 mod bus;
+mod cmdline;
 mod drivers;
 mod dummy;
+mod inventory;
+mod irq;
+#[cfg(feature = "partition")]
+mod partition;
+mod power;
+mod probe;
 mod structs;
 
 #[cfg(feature = "virtio")]
@@ -80,80 +100,359 @@ mod ixgbe;
 
 pub mod prelude;
 
+#[cfg(feature = "dma")]
+pub mod dma;
+
+#[cfg(feature = "block-cache")]
+pub use self::block_cache::{BlockCacheConfig, BlockCacheStats};
+pub use self::cmdline::driver_param;
+pub use self::drivers::{DriverEntry, DriverProbe, DRIVERS};
+pub use self::inventory::{ProbeError, ScanStatus, ScannedDevice};
+pub use self::irq::{
+    handle_irq, register_irq_handler, unregister_irq_handler, DeviceIrq, IrqHandler, MAX_IRQS,
+};
+#[cfg(feature = "partition")]
+pub use self::partition::{Guid, PartitionInfo, PartitionKind};
 #[allow(unused_imports)]
 use self::prelude::*;
-pub use self::structs::{AxDeviceContainer, AxDeviceEnum};
+use self::probe::{DeferredProbes, ProbeSite};
+pub use self::probe::{Dependency, ProbeResult};
+pub use self::structs::{
+    AxDeviceContainer, AxDeviceEnum, AxDeviceInfo, DeviceLocation, DeviceName, DeviceNaming,
+};
+use arrayvec::ArrayVec;
+
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+pub use self::structs::AxDynDeviceContainer;
+pub use self::structs::MAX_DEVICES;
 
+#[cfg(bus = "pci")]
+pub use self::bus::{MsiDomain, PciDeviceId, PciIrq, PCI_ANY_ID};
+
+#[cfg(feature = "block")]
+pub use self::block::{BlockDriverAdapter, BlockRequestOps};
 #[cfg(feature = "block")]
-pub use self::structs::AxBlockDevice;
+pub use self::structs::{AxBlockDevice, AxBlockDevices};
 #[cfg(feature = "display")]
-pub use self::structs::AxDisplayDevice;
+pub use self::structs::{AxDisplayDevice, AxDisplayDevices};
 #[cfg(feature = "net")]
-pub use self::structs::AxNetDevice;
+pub use self::structs::{AxNetDevice, AxNetDevices};
+
+/// Boot-time arguments passed to [`init_drivers_with`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InitArgs {
+    /// Physical address of the flattened device tree blob, if provided by the
+    /// bootloader.
+    pub dtb_paddr: Option<usize>,
+    /// The kernel command line, `axdriver.*` parameters in it select drivers
+    /// and set their options. See the [crate-level documentation](crate#driver-selection).
+    pub cmdline: &'static str,
+    /// How PCI functions raise message signaled interrupts, e.g.
+    /// [`MsiDomain::X86_LAPIC`]. If not given, MSI and MSI-X are left
+    /// disabled and functions use INTx. Even if given, they are only set up
+    /// for functions whose drivers program the vectors, see
+    /// [`DriverProbe::MSI`].
+    #[cfg(bus = "pci")]
+    pub msi: Option<MsiDomain>,
+    /// Whether DMA is coherent with the CPU caches, see [`dma::cache`].
+    #[cfg(feature = "dma")]
+    pub dma_coherency: dma::DmaCoherency,
+    /// The pool of bounce buffers for DMA, see [`dma::swiotlb`].
+    #[cfg(feature = "swiotlb")]
+    pub swiotlb: Option<dma::swiotlb::SwiotlbArgs>,
+    /// The IOMMU that PCI devices are behind, see [`dma::iommu`].
+    #[cfg(feature = "iommu")]
+    pub iommu: Option<dma::iommu::IommuArgs>,
+    /// The cache put around each block device, none if not given. See
+    /// [`BlockCacheConfig`].
+    #[cfg(feature = "block-cache")]
+    pub block_cache: Option<BlockCacheConfig>,
+}
 
 /// A structure that contains all device drivers, organized by their category.
 #[derive(Default)]
 pub struct AllDevices {
     /// All network device drivers.
     #[cfg(feature = "net")]
-    pub net: AxDeviceContainer<AxNetDevice>,
+    pub net: AxNetDevices,
     /// All block device drivers.
     #[cfg(feature = "block")]
-    pub block: AxDeviceContainer<AxBlockDevice>,
+    pub block: AxBlockDevices,
     /// All graphics device drivers.
     #[cfg(feature = "display")]
-    pub display: AxDeviceContainer<AxDisplayDevice>,
+    pub display: AxDisplayDevices,
+    /// Number of devices named with each prefix so far.
+    name_counters: ArrayVec<(&'static str, usize), MAX_NAME_PREFIXES>,
+    /// Number of devices registered so far.
+    num_probed: usize,
+    /// All devices scanned on the buses.
+    inventory: inventory::Inventory,
+    /// All devices bound to drivers, in probe order.
+    probed: power::ProbeOrder,
+    /// All partitions registered as block devices.
+    #[cfg(feature = "partition")]
+    partitions: alloc::vec::Vec<PartitionInfo>,
+    /// The counters of the block caches.
+    #[cfg(feature = "block-cache")]
+    block_caches: block_cache::CacheList,
+    /// The PCI host bridge state, for releasing resources on unbind.
+    #[cfg(bus = "pci")]
+    pci: Option<bus::PciHost>,
 }
 
+/// The device model of the enabled device categories, returned by
+/// [`AllDevices::device_model`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceModel {
+    /// All categories use the static model.
+    Static,
+    /// All categories use the dynamic model.
+    Dyn,
+    /// Some categories use the static model, others the dynamic model.
+    Mixed,
+}
+
+/// Maximum number of different device name prefixes, e.g. `eth` and `vd`.
+const MAX_NAME_PREFIXES: usize = 16;
+
 #[cfg(feature = "img")]
 core::arch::global_asm!(include_str!("../image.S"));
 
 impl AllDevices {
-    /// Returns the device model used, either `dyn` or `static`.
+    /// Returns the device model used by the enabled device categories.
     ///
     /// See the [crate-level documentation](crate) for more details.
-    pub const fn device_model() -> &'static str {
-        if cfg!(feature = "dyn") {
-            "dyn"
-        } else {
-            "static"
+    pub const fn device_model() -> DeviceModel {
+        let models = [
+            (cfg!(feature = "net"), cfg!(net_dyn)),
+            (cfg!(feature = "block"), cfg!(block_dyn)),
+            (cfg!(feature = "display"), cfg!(display_dyn)),
+        ];
+        let (mut any_dyn, mut any_static) = (false, false);
+        let mut i = 0;
+        while i < models.len() {
+            if models[i].0 {
+                any_dyn |= models[i].1;
+                any_static |= !models[i].1;
+            }
+            i += 1;
+        }
+        match (any_dyn, any_static) {
+            (true, true) => DeviceModel::Mixed,
+            (true, false) => DeviceModel::Dyn,
+            _ => DeviceModel::Static,
         }
     }
 
     /// Probes all supported devices.
-    fn probe(&mut self) {
-        for_each_drivers!(type Driver, {
-            if let Some(dev) = Driver::probe_global() {
-                info!(
-                    "registered a new {:?} device: {:?}",
-                    dev.device_type(),
-                    dev.device_name(),
-                );
-                self.add_device(dev);
-            }
-        });
+    fn probe(&mut self, args: &InitArgs) {
+        let mut deferred = DeferredProbes::default();
+        for driver in cmdline::drivers() {
+            let info = AxDeviceInfo::default();
+            self.probe_site(
+                core::iter::once(driver),
+                ProbeSite::Global,
+                info,
+                &mut deferred,
+            );
+        }
 
-        self.probe_bus_devices();
+        self.probe_bus_devices(args, &mut deferred);
+        self.probe_deferred(&mut deferred);
     }
 
     /// Adds one device into the corresponding container, according to its device category.
+    ///
+    /// The device is named by `naming` or the default naming of its category,
+    /// the name and probe order in `info` are overwritten.
     #[allow(dead_code)]
-    fn add_device(&mut self, dev: AxDeviceEnum) {
+    fn add_device(
+        &mut self,
+        dev: AxDeviceEnum,
+        naming: Option<DeviceNaming>,
+        mut info: AxDeviceInfo,
+    ) {
+        let naming = naming.unwrap_or_else(|| DeviceNaming::default_for(dev.device_type()));
+        info.name = self.next_name(naming);
+        info.probe_order = self.num_probed;
+        self.num_probed += 1;
+        debug!("device {} at {}", info.name, info.location);
         match dev {
             #[cfg(feature = "net")]
-            AxDeviceEnum::Net(dev) => self.net.push(dev),
+            AxDeviceEnum::Net(dev) => self.net.push(dev, info),
             #[cfg(feature = "block")]
-            AxDeviceEnum::Block(dev) => self.block.push(dev),
+            AxDeviceEnum::Block(dev) => self.block.push(dev, info),
             #[cfg(feature = "display")]
-            AxDeviceEnum::Display(dev) => self.display.push(dev),
+            AxDeviceEnum::Display(dev) => self.display.push(dev, info),
         }
     }
+
+    /// Returns the name for the next device named by `naming`.
+    fn next_name(&mut self, naming: DeviceNaming) -> DeviceName {
+        let prefix = naming.prefix();
+        let index = match self.name_counters.iter_mut().find(|(p, _)| *p == prefix) {
+            Some((_, count)) => {
+                *count += 1;
+                *count - 1
+            }
+            None => {
+                if self.name_counters.try_push((prefix, 1)).is_err() {
+                    warn!("too many device name prefixes, {:?} is not counted", prefix);
+                }
+                0
+            }
+        };
+        naming.name(index)
+    }
+
+    /// Returns the network device named `name`, e.g. `eth0`.
+    #[cfg(feature = "net")]
+    pub fn net_by_name(&self, name: &str) -> Option<&AxNetDevice> {
+        self.net.get_by_name(name)
+    }
+
+    /// Returns the block device named `name`, e.g. `vda`.
+    #[cfg(feature = "block")]
+    pub fn block_by_name(&self, name: &str) -> Option<&AxBlockDevice> {
+        self.block.get_by_name(name)
+    }
+
+    /// Returns the graphics display device named `name`, e.g. `fb0`.
+    #[cfg(feature = "display")]
+    pub fn display_by_name(&self, name: &str) -> Option<&AxDisplayDevice> {
+        self.display.get_by_name(name)
+    }
+
+    /// Returns the information of the device at `location`, in any category.
+    ///
+    /// The device itself can then be looked up by the returned name.
+    pub fn info_by_location(&self, location: &DeviceLocation) -> Option<&AxDeviceInfo> {
+        #[cfg(feature = "net")]
+        if let Some(i) = self.net.position_by_location(location) {
+            return self.net.info(i);
+        }
+        #[cfg(feature = "block")]
+        if let Some(i) = self.block.position_by_location(location) {
+            return self.block.info(i);
+        }
+        #[cfg(feature = "display")]
+        if let Some(i) = self.display.position_by_location(location) {
+            return self.display.info(i);
+        }
+        let _ = location;
+        None
+    }
+
+    /// Unbinds the device named `name` and removes it from `self`.
+    ///
+    /// The device is quiesced first (for PCI functions: bus mastering and
+    /// interrupts are disabled), then its driver is dropped, which frees the
+    /// DMA memory of the driver. At last the bus resources, such as PCI BAR
+    /// ranges and MSI IRQs, are released. Interrupt handlers registered for the device
+    /// should be unregistered by the caller before.
+    ///
+    /// Unbinding a disk also removes its partitions, while unbinding a
+    /// partition only removes the partition device. A disk whose partitions
+    /// are taken out of `self` can't be unbound, [`DevError::ResourceBusy`]
+    /// is returned.
+    ///
+    /// Returns the information of the removed device.
+    pub fn unbind(&mut self, name: &str) -> DevResult<AxDeviceInfo> {
+        #[cfg(feature = "partition")]
+        if let Some(info) = self.unbind_partition(name) {
+            return Ok(info);
+        }
+        #[cfg(feature = "partition")]
+        if let Some(partition) = self.taken_partition_of(name) {
+            warn!(
+                "unbind: partition {} of {} is still in use",
+                partition, name
+            );
+            return Err(DevError::ResourceBusy);
+        }
+        #[cfg(feature = "net")]
+        if let Some((dev, info)) = self.net.take_by_name(name) {
+            return Ok(self.teardown(dev, info));
+        }
+        #[cfg(feature = "block")]
+        if let Some((mut dev, info)) = self.block.take_by_name(name) {
+            #[cfg(feature = "partition")]
+            self.unbind_partitions_of(name);
+            // Write cached blocks back while the device can still do DMA.
+            if let Err(e) = dev.flush() {
+                warn!("failed to flush {}: {:?}", name, e);
+            }
+            return Ok(self.teardown(dev, info));
+        }
+        #[cfg(feature = "display")]
+        if let Some((dev, info)) = self.display.take_by_name(name) {
+            return Ok(self.teardown(dev, info));
+        }
+        warn!("unbind: no device named {:?}", name);
+        Err(DevError::InvalidParam)
+    }
+
+    /// Unbinds the device at the PCI function `bus:device.function`, e.g. on
+    /// a PCIe hot-unplug event or a virtio-pci surprise removal.
+    ///
+    /// See [`unbind`](Self::unbind).
+    pub fn unbind_pci(&mut self, bus: u8, device: u8, function: u8) -> DevResult<AxDeviceInfo> {
+        let name = self
+            .by_pci_bdf(bus, device, function)
+            .ok_or(DevError::InvalidParam)?
+            .name;
+        self.unbind(&name)
+    }
+
+    #[allow(dead_code)]
+    fn teardown<D>(&mut self, dev: D, info: AxDeviceInfo) -> AxDeviceInfo {
+        info!("unbinding device {} at {}", info.name, info.location);
+        self.quiesce_bus_device(&info.location);
+        drop(dev);
+        #[cfg(feature = "dma-debug")]
+        crate::dma::debug::report(Some(&info.location));
+        #[cfg(feature = "dma")]
+        crate::dma::release_device(info.driver, &info.location);
+        self.release_bus_device(&info.location);
+        self.inventory
+            .set_status(&info.location, ScanStatus::Unbound);
+        self.probed.remove(info.probe_order);
+        info
+    }
+
+    /// Returns the information of the PCI function at `bus:device.function`.
+    pub fn by_pci_bdf(&self, bus: u8, device: u8, function: u8) -> Option<&AxDeviceInfo> {
+        self.info_by_location(&DeviceLocation::Pci {
+            bus,
+            device,
+            function,
+        })
+    }
 }
 
 /// Probes and initializes all device drivers, returns the [`AllDevices`] struct.
 pub fn init_drivers() -> AllDevices {
+    init_drivers_with(&InitArgs::default())
+}
+
+/// Same as [`init_drivers`], but with boot-time arguments such as the device
+/// tree blob.
+pub fn init_drivers_with(args: &InitArgs) -> AllDevices {
     info!("Initialize device drivers...");
-    info!("  device model: {}", AllDevices::device_model());
+    info!("  device model: {:?}", AllDevices::device_model());
+    cmdline::init(args.cmdline);
+    #[cfg(feature = "dma")]
+    dma::cache::init(&args.dma_coherency);
+    #[cfg(feature = "swiotlb")]
+    if let Some(swiotlb) = &args.swiotlb {
+        dma::swiotlb::init(swiotlb);
+    }
+    #[cfg(feature = "iommu")]
+    if let Some(iommu) = &args.iommu {
+        if let Err(e) = dma::iommu::init(iommu) {
+            warn!("failed to enable the IOMMU: {:?}", e);
+        }
+    }
 
     let mut all_devs = AllDevices::default();
 
@@ -178,33 +477,56 @@ pub fn init_drivers() -> AllDevices {
         // unsafe {
         //     ram_disk.copy_from_slice((TESTCASE_MEMORY_START + PHYS_VIRT_OFFSET) as *const u8)
         // };
-        all_devs.add_device(AxDeviceEnum::from_block(ram_disk));
+        all_devs.add_device(
+            AxDeviceEnum::from_block_requests(ram_disk),
+            Some(DeviceNaming::Numbered("ram")),
+            AxDeviceInfo {
+                driver: "ramdisk",
+                ..Default::default()
+            },
+        );
     }
 
-    all_devs.probe();
+    all_devs.probe(args);
+    #[cfg(feature = "block-cache")]
+    if let Some(config) = &args.block_cache {
+        all_devs.add_block_caches(config);
+    }
+    #[cfg(feature = "partition")]
+    all_devs.scan_partitions();
 
     #[cfg(feature = "net")]
     {
         debug!("number of NICs: {}", all_devs.net.len());
-        for (i, dev) in all_devs.net.iter().enumerate() {
+        for (i, (dev, info)) in all_devs.net.iter_with_info().enumerate() {
             assert_eq!(dev.device_type(), DeviceType::Net);
-            debug!("  NIC {}: {:?}", i, dev.device_name());
+            debug!("  NIC {} ({}): {:?}", i, info.name, dev.device_name());
         }
     }
     #[cfg(feature = "block")]
     {
         debug!("number of block devices: {}", all_devs.block.len());
-        for (i, dev) in all_devs.block.iter().enumerate() {
+        for (i, (dev, info)) in all_devs.block.iter_with_info().enumerate() {
             assert_eq!(dev.device_type(), DeviceType::Block);
-            debug!("  block device {}: {:?}", i, dev.device_name());
+            debug!(
+                "  block device {} ({}): {:?}",
+                i,
+                info.name,
+                dev.device_name()
+            );
         }
     }
     #[cfg(feature = "display")]
     {
         debug!("number of graphics devices: {}", all_devs.display.len());
-        for (i, dev) in all_devs.display.iter().enumerate() {
+        for (i, (dev, info)) in all_devs.display.iter_with_info().enumerate() {
             assert_eq!(dev.device_type(), DeviceType::Display);
-            debug!("  graphics device {}: {:?}", i, dev.device_name());
+            debug!(
+                "  graphics device {} ({}): {:?}",
+                i,
+                info.name,
+                dev.device_name()
+            );
         }
     }
 
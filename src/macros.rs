@@ -1,73 +1,63 @@
-//! TODO: generate registered drivers in `for_each_drivers!` automatically.
+//! Macros to register drivers.
 
 #![allow(unused_macros)]
 
+/// Registers a driver that implements [`DriverProbe`], so that it's probed by
+/// [`init_drivers`].
+///
+/// The driver is put into the [`DRIVERS`] table by the linker, so drivers
+/// from other crates can be registered the same way. The linker script must
+/// keep the `linkme_DRIVERS` sections. They should construct
+/// their devices with [`AxDeviceEnum`], which only accepts foreign device
+/// types in the dynamic device model. Foreign block devices that implement
+/// [`BlockRequestOps`] are given to [`AxDeviceEnum::from_block_requests`].
+///
+/// ```ignore
+/// struct MyDriver;
+/// impl axdriver::DriverProbe for MyDriver { /* ... */ }
+/// axdriver::register_driver!("my-driver", MyDriver);
+/// ```
+///
+/// [`DriverProbe`]: crate::DriverProbe
+/// [`init_drivers`]: crate::init_drivers
+/// [`DRIVERS`]: crate::DRIVERS
+/// [`AxDeviceEnum`]: crate::AxDeviceEnum
+/// [`AxDeviceEnum::from_block_requests`]: crate::AxDeviceEnum::from_block_requests
+/// [`BlockRequestOps`]: crate::BlockRequestOps
+#[macro_export]
+macro_rules! register_driver {
+    ($name:expr, $driver_type:ty) => {
+        const _: () = {
+            #[$crate::__linkme::distributed_slice($crate::DRIVERS)]
+            #[linkme(crate = $crate::__linkme)]
+            static DRIVER: $crate::DriverEntry = $crate::DriverEntry::new::<$driver_type>($name);
+        };
+    };
+}
+
 macro_rules! register_net_driver {
-    ($driver_type:ty, $device_type:ty) => {
+    ($name:expr, $driver_type:ty, $device_type:ty) => {
         /// The unified type of the NIC devices.
-        #[cfg(not(feature = "dyn"))]
+        #[cfg(not(net_dyn))]
         pub type AxNetDevice = $device_type;
+        register_driver!($name, $driver_type);
     };
 }
 
 macro_rules! register_block_driver {
-    ($driver_type:ty, $device_type:ty) => {
-        /// The unified type of the NIC devices.
-        #[cfg(not(feature = "dyn"))]
+    ($name:expr, $driver_type:ty, $device_type:ty) => {
+        /// The unified type of the block storage devices.
+        #[cfg(not(block_dyn))]
         pub type AxBlockDevice = $device_type;
+        register_driver!($name, $driver_type);
     };
 }
 
 macro_rules! register_display_driver {
-    ($driver_type:ty, $device_type:ty) => {
-        /// The unified type of the NIC devices.
-        #[cfg(not(feature = "dyn"))]
+    ($name:expr, $driver_type:ty, $device_type:ty) => {
+        /// The unified type of the graphics display devices.
+        #[cfg(not(display_dyn))]
         pub type AxDisplayDevice = $device_type;
+        register_driver!($name, $driver_type);
     };
 }
-
-macro_rules! for_each_drivers {
-    (type $drv_type:ident, $code:block) => {{
-        #[allow(unused_imports)]
-        use crate::drivers::DriverProbe;
-        #[cfg(feature = "virtio")]
-        #[allow(unused_imports)]
-        use crate::virtio::{self, VirtIoDevMeta};
-
-        #[cfg(net_dev = "virtio-net")]
-        {
-            type $drv_type = <virtio::VirtIoNet as VirtIoDevMeta>::Driver;
-            $code
-        }
-        #[cfg(block_dev = "virtio-blk")]
-        {
-            type $drv_type = <virtio::VirtIoBlk as VirtIoDevMeta>::Driver;
-            $code
-        }
-        #[cfg(display_dev = "virtio-gpu")]
-        {
-            type $drv_type = <virtio::VirtIoGpu as VirtIoDevMeta>::Driver;
-            $code
-        }
-        #[cfg(block_dev = "ramdisk")]
-        {
-            type $drv_type = crate::drivers::RamDiskDriver;
-            $code
-        }
-        #[cfg(block_dev = "bcm2835-sdhci")]
-        {
-            type $drv_type = crate::drivers::BcmSdhciDriver;
-            $code
-        }
-        #[cfg(net_dev = "ixgbe")]
-        {
-            type $drv_type = crate::drivers::IxgbeDriver;
-            $code
-        }
-        #[cfg(net_dev = "e1000")]
-        {
-            type $drv_type = crate::drivers::E1000Driver;
-            $code
-        }
-    }};
-}
@@ -0,0 +1,348 @@
+//! GUID partition tables.
+//!
+//! Both headers are checked: the primary one at block 1 and the backup one
+//! at the last block. If one of them or its entry array is corrupted, the
+//! other one is used.
+
+use alloc::{vec, vec::Vec};
+
+use arrayvec::ArrayString;
+
+use super::{read_block, Guid, PartitionInfo, PartitionKind};
+use crate::{prelude::*, DeviceName};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the header fields, up to the CRC of the entries.
+const HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+/// Maximum size of the entry array, 128 entries of 128 bytes are usual.
+const MAX_ENTRIES_SIZE: usize = 0x10_0000;
+/// Number of UTF-16 code units of a partition name.
+const NAME_LEN: usize = 36;
+
+/// The CRC-32 used by GPT: IEEE 802.3, reflected.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &b| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize]
+    })
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid_at(buf: &[u8], offset: usize) -> Guid {
+    Guid(buf[offset..offset + 16].try_into().unwrap())
+}
+
+/// The fields of a GPT header that are used.
+struct Header {
+    alternate_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    num_entries: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Reads and checks the header at `lba` and its entry array.
+fn read_header(disk: &mut dyn BlockRequestOps, lba: u64) -> DevResult<(Header, Vec<u8>)> {
+    let block = read_block(disk, lba)?;
+    let bad = |what: &str| {
+        warn!("GPT: header at block {}: {}", lba, what);
+        DevError::BadState
+    };
+    if &block[..8] != SIGNATURE {
+        return Err(bad("no signature"));
+    }
+    let header_size = u32_at(&block, 12) as usize;
+    if !(HEADER_SIZE..=block.len()).contains(&header_size) {
+        return Err(bad("bad header size"));
+    }
+    let mut header_bytes = Vec::from(&block[..header_size]);
+    header_bytes[16..20].fill(0);
+    if crc32(&header_bytes) != u32_at(&block, 16) {
+        return Err(bad("bad header CRC"));
+    }
+    if u64_at(&block, 24) != lba {
+        return Err(bad("wrong location"));
+    }
+    let header = Header {
+        alternate_lba: u64_at(&block, 32),
+        first_usable: u64_at(&block, 40),
+        last_usable: u64_at(&block, 48),
+        entries_lba: u64_at(&block, 72),
+        num_entries: u32_at(&block, 80) as usize,
+        entry_size: u32_at(&block, 84) as usize,
+        entries_crc: u32_at(&block, 88),
+    };
+    let entries_size = header
+        .num_entries
+        .checked_mul(header.entry_size)
+        .filter(|&size| size <= MAX_ENTRIES_SIZE);
+    let Some(entries_size) = entries_size else {
+        return Err(bad("bad entry array"));
+    };
+    if header.entry_size < MIN_ENTRY_SIZE || !header.entry_size.is_power_of_two() {
+        return Err(bad("bad entry size"));
+    }
+
+    let mut entries = vec![0; entries_size.next_multiple_of(block.len())];
+    disk.read_blocks(header.entries_lba, &mut entries)?;
+    entries.truncate(entries_size);
+    if crc32(&entries) != header.entries_crc {
+        return Err(bad("bad entry array CRC"));
+    }
+    Ok((header, entries))
+}
+
+/// Decodes a UTF-16 partition name, up to the first NUL.
+fn label(name: &[u8]) -> ArrayString<108> {
+    let units = name
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0);
+    let mut label = ArrayString::new();
+    for c in char::decode_utf16(units) {
+        if label
+            .try_push(c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .is_err()
+        {
+            break;
+        }
+    }
+    label
+}
+
+/// Returns the partitions of the GPT on `disk`.
+pub(super) fn parse(disk: &mut dyn BlockRequestOps) -> DevResult<Vec<PartitionInfo>> {
+    let num_blocks = disk.num_blocks();
+    let primary = read_header(disk, 1);
+    let backup_lba = match &primary {
+        Ok((header, _)) => header.alternate_lba,
+        Err(_) => num_blocks - 1,
+    };
+    let backup = if backup_lba < num_blocks {
+        read_header(disk, backup_lba)
+    } else {
+        Err(DevError::BadState)
+    };
+    let (header, entries) = match (primary, backup) {
+        (Ok(primary), Ok(_)) => primary,
+        (Ok(primary), Err(_)) => {
+            warn!("GPT: the backup header is corrupted, using the primary one");
+            primary
+        }
+        (Err(_), Ok(backup)) => {
+            warn!("GPT: the primary header is corrupted, using the backup one");
+            backup
+        }
+        (Err(e), Err(_)) => return Err(e),
+    };
+
+    let last_usable = header.last_usable.min(num_blocks.saturating_sub(1));
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(header.entry_size).enumerate() {
+        let type_guid = guid_at(entry, 0);
+        if type_guid.is_zero() {
+            continue;
+        }
+        let number = i as u32 + 1;
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if first < header.first_usable || last > last_usable || first > last {
+            warn!(
+                "GPT: partition {} [{}, {}] is out of the usable blocks, skipped",
+                number, first, last
+            );
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            name: DeviceName::default(),
+            disk: DeviceName::default(),
+            number,
+            start_block: first,
+            num_blocks: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: guid_at(entry, 16),
+                attributes: u64_at(entry, 48),
+            },
+            label: label(&entry[56..56 + NAME_LEN * 2]),
+        });
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::MemDisk;
+
+    const BS: usize = 512;
+    const NUM_BLOCKS: u64 = 64;
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn entry(type_byte: u8, first: u64, last: u64, name: &str) -> [u8; MIN_ENTRY_SIZE] {
+        let mut e = [0; MIN_ENTRY_SIZE];
+        e[0] = type_byte;
+        e[16] = 0xaa;
+        e[32..40].copy_from_slice(&first.to_le_bytes());
+        e[40..48].copy_from_slice(&last.to_le_bytes());
+        e[48..56].copy_from_slice(&1u64.to_le_bytes());
+        let name = utf16(name);
+        e[56..56 + name.len()].copy_from_slice(&name);
+        e
+    }
+
+    /// Writes a header at `lba` with its entry array at `entries_lba`.
+    fn write_header(disk: &MemDisk, lba: u64, alternate: u64, entries_lba: u64, entries: &[u8]) {
+        let mut h = [0; HEADER_SIZE];
+        h[..8].copy_from_slice(SIGNATURE);
+        h[8..12].copy_from_slice(&0x1_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        h[24..32].copy_from_slice(&lba.to_le_bytes());
+        h[32..40].copy_from_slice(&alternate.to_le_bytes());
+        h[40..48].copy_from_slice(&3u64.to_le_bytes());
+        h[48..56].copy_from_slice(&(NUM_BLOCKS - 3).to_le_bytes());
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&((entries.len() / MIN_ENTRY_SIZE) as u32).to_le_bytes());
+        h[84..88].copy_from_slice(&(MIN_ENTRY_SIZE as u32).to_le_bytes());
+        h[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&h);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.set(lba as usize * BS, &h);
+        disk.set(entries_lba as usize * BS, entries);
+    }
+
+    /// A disk with both headers and 4 entries, 2 of them used.
+    fn gpt_disk() -> MemDisk {
+        let disk = MemDisk::new(BS, NUM_BLOCKS);
+        let mut entries = Vec::new();
+        entries.extend(entry(1, 3, 10, "boot"));
+        entries.extend([0; MIN_ENTRY_SIZE]);
+        entries.extend(entry(2, 11, 60, "root"));
+        entries.extend([0; MIN_ENTRY_SIZE]);
+        write_header(&disk, 1, NUM_BLOCKS - 1, 2, &entries);
+        write_header(&disk, NUM_BLOCKS - 1, 1, NUM_BLOCKS - 2, &entries);
+        disk
+    }
+
+    fn check_partitions(partitions: &[PartitionInfo]) {
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].number, 1);
+        assert_eq!(partitions[0].start_block, 3);
+        assert_eq!(partitions[0].num_blocks, 8);
+        assert_eq!(partitions[0].label.as_str(), "boot");
+        assert_eq!(partitions[1].number, 3);
+        assert_eq!(partitions[1].start_block, 11);
+        assert_eq!(partitions[1].num_blocks, 50);
+        assert_eq!(partitions[1].label.as_str(), "root");
+        let PartitionKind::Gpt {
+            type_guid,
+            unique_guid,
+            attributes,
+        } = partitions[1].kind
+        else {
+            panic!("not a GPT partition");
+        };
+        assert_eq!(type_guid.0[0], 2);
+        assert_eq!(unique_guid.0[0], 0xaa);
+        assert_eq!(attributes, 1);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn label_decoding() {
+        let mut name = utf16("EFI system");
+        name.resize(NAME_LEN * 2, 0);
+        assert_eq!(label(&name).as_str(), "EFI system");
+        // A character out of the BMP, in a surrogate pair.
+        assert_eq!(label(&utf16("a\u{1f600}b")).as_str(), "a\u{1f600}b");
+        // An unpaired surrogate.
+        assert_eq!(label(&[0x00, 0xd8, 0x41, 0x00]).as_str(), "\u{fffd}A");
+        assert_eq!(label(&[0; NAME_LEN * 2]).as_str(), "");
+    }
+
+    #[test]
+    fn both_headers() {
+        check_partitions(&parse(&mut gpt_disk()).unwrap());
+    }
+
+    #[test]
+    fn corrupted_primary_header() {
+        let mut disk = gpt_disk();
+        disk.set(BS + 40, &[0xff]);
+        check_partitions(&parse(&mut disk).unwrap());
+    }
+
+    #[test]
+    fn corrupted_primary_entries() {
+        let mut disk = gpt_disk();
+        disk.set(2 * BS + 56, &[0xff]);
+        check_partitions(&parse(&mut disk).unwrap());
+    }
+
+    #[test]
+    fn corrupted_backup_header() {
+        let mut disk = gpt_disk();
+        disk.set((NUM_BLOCKS - 1) as usize * BS, b"NOT PART");
+        check_partitions(&parse(&mut disk).unwrap());
+    }
+
+    #[test]
+    fn both_headers_corrupted() {
+        let mut disk = gpt_disk();
+        disk.set(BS + 40, &[0xff]);
+        disk.set((NUM_BLOCKS - 2) as usize * BS, &[0xff]);
+        assert!(parse(&mut disk).is_err());
+    }
+
+    #[test]
+    fn partition_out_of_usable_blocks() {
+        let disk = MemDisk::new(BS, NUM_BLOCKS);
+        let mut entries = Vec::new();
+        entries.extend(entry(1, 3, NUM_BLOCKS - 1, "too big"));
+        entries.extend(entry(1, 20, 10, "reversed"));
+        entries.extend(entry(1, 4, 5, "ok"));
+        entries.extend([0; MIN_ENTRY_SIZE]);
+        write_header(&disk, 1, NUM_BLOCKS - 1, 2, &entries);
+        write_header(&disk, NUM_BLOCKS - 1, 1, NUM_BLOCKS - 2, &entries);
+        let partitions = parse(&mut disk.clone()).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].number, 3);
+        assert_eq!(partitions[0].label.as_str(), "ok");
+    }
+}
@@ -0,0 +1,256 @@
+//! Master boot record partition tables, with extended partitions.
+
+use alloc::vec::Vec;
+
+use arrayvec::ArrayString;
+
+use super::{read_block, PartitionInfo, PartitionKind};
+use crate::{prelude::*, DeviceName};
+
+/// The type of the only partition of a protective MBR, in front of a GPT.
+pub(super) const GPT_PROTECTIVE: u8 = 0xee;
+/// Types of extended partitions, which hold a chain of logical partitions.
+const EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Offset of the partition table in the MBR and in each EBR.
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+/// Maximum number of logical partitions, to stop at a loop in the chain.
+const MAX_LOGICAL: u32 = 128;
+
+/// An entry of a partition table, in blocks.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MbrEntry {
+    bootable: bool,
+    pub system_id: u8,
+    start: u64,
+    count: u64,
+}
+
+/// Returns the entries of the MBR or EBR in `sector`, or `None` if it has no
+/// boot signature.
+pub(super) fn parse_mbr(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    Some(core::array::from_fn(|i| {
+        let e = &sector[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        MbrEntry {
+            bootable: e[0] & 0x80 != 0,
+            system_id: e[4],
+            start: u32::from_le_bytes(e[8..12].try_into().unwrap()) as u64,
+            count: u32::from_le_bytes(e[12..16].try_into().unwrap()) as u64,
+        }
+    }))
+}
+
+impl MbrEntry {
+    fn is_used(&self) -> bool {
+        self.system_id != 0 && self.count != 0
+    }
+
+    /// Returns the partition of the entry, whose start is relative to `base`,
+    /// if it fits on a disk of `num_blocks` blocks.
+    fn partition(&self, number: u32, base: u64, num_blocks: u64) -> Option<PartitionInfo> {
+        let start = base.checked_add(self.start);
+        let end = start.and_then(|start| start.checked_add(self.count));
+        let (Some(start), Some(end)) = (start, end) else {
+            warn!(
+                "MBR: partition {} overflows the block numbers, skipped",
+                number
+            );
+            return None;
+        };
+        if self.start == 0 || end > num_blocks {
+            warn!(
+                "MBR: partition {} [{}, {}) is out of the disk ({} blocks), skipped",
+                number, start, end, num_blocks
+            );
+            return None;
+        }
+        Some(PartitionInfo {
+            name: DeviceName::default(),
+            disk: DeviceName::default(),
+            number,
+            start_block: start,
+            num_blocks: self.count,
+            kind: PartitionKind::Mbr {
+                system_id: self.system_id,
+                bootable: self.bootable,
+            },
+            label: ArrayString::new(),
+        })
+    }
+}
+
+/// Returns the partitions of an MBR with `entries`, numbered by their slot,
+/// and the logical partitions of its extended partition from 5.
+pub(super) fn parse(
+    disk: &mut dyn BlockRequestOps,
+    entries: &[MbrEntry; 4],
+) -> DevResult<Vec<PartitionInfo>> {
+    let num_blocks = disk.num_blocks();
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, entry) in entries.iter().enumerate() {
+        if !entry.is_used() {
+            continue;
+        }
+        if EXTENDED.contains(&entry.system_id) {
+            extended.get_or_insert(*entry);
+            continue;
+        }
+        partitions.extend(entry.partition(i as u32 + 1, 0, num_blocks));
+    }
+    if let Some(extended) = extended {
+        parse_logical(disk, &extended, num_blocks, &mut partitions)?;
+    }
+    Ok(partitions)
+}
+
+/// Follows the chain of EBRs in `extended`.
+///
+/// The logical partition of each EBR starts relative to the EBR, and the
+/// next EBR relative to the extended partition.
+fn parse_logical(
+    disk: &mut dyn BlockRequestOps,
+    extended: &MbrEntry,
+    num_blocks: u64,
+    partitions: &mut Vec<PartitionInfo>,
+) -> DevResult {
+    let mut ebr = extended.start;
+    for number in 5..5 + MAX_LOGICAL {
+        if ebr >= num_blocks {
+            warn!("MBR: EBR at block {} is out of the disk", ebr);
+            break;
+        }
+        let Some(entries) = parse_mbr(&read_block(disk, ebr)?) else {
+            warn!("MBR: no EBR at block {}", ebr);
+            break;
+        };
+        if entries[0].is_used() {
+            partitions.extend(entries[0].partition(number, ebr, num_blocks));
+        }
+        if !entries[1].is_used() {
+            break;
+        }
+        ebr = extended.start + entries[1].start;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::MemDisk;
+
+    const BS: usize = 512;
+
+    /// Writes a table with `entries` of (system ID, start, count) and the
+    /// boot signature in the block at `lba`.
+    fn write_table(disk: &MemDisk, lba: u64, entries: &[(u8, u32, u32)]) {
+        let mut sector = [0; BS];
+        for (i, &(system_id, start, count)) in entries.iter().enumerate() {
+            let e = &mut sector[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+            e[0] = if i == 0 { 0x80 } else { 0 };
+            e[4] = system_id;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&count.to_le_bytes());
+        }
+        sector[510..].copy_from_slice(&[0x55, 0xaa]);
+        disk.set(lba as usize * BS, &sector);
+    }
+
+    fn parse_disk(disk: &mut MemDisk) -> Vec<PartitionInfo> {
+        let entries = parse_mbr(&disk.get(0, BS)).unwrap();
+        parse(disk, &entries).unwrap()
+    }
+
+    fn span(p: &PartitionInfo) -> (u32, u64, u64) {
+        (p.number, p.start_block, p.num_blocks)
+    }
+
+    #[test]
+    fn no_signature() {
+        assert!(parse_mbr(&[0; BS]).is_none());
+    }
+
+    #[test]
+    fn entries() {
+        let disk = MemDisk::new(BS, 1);
+        write_table(&disk, 0, &[(0x83, 2048, 4096), (0xee, 1, 0xffff_ffff)]);
+        let entries = parse_mbr(&disk.get(0, BS)).unwrap();
+        assert!(entries[0].bootable);
+        assert_eq!(entries[0].system_id, 0x83);
+        assert_eq!(entries[0].start, 2048);
+        assert_eq!(entries[0].count, 4096);
+        assert!(!entries[1].bootable);
+        assert_eq!(entries[1].system_id, GPT_PROTECTIVE);
+        assert_eq!(entries[1].count, 0xffff_ffff);
+        assert!(!entries[2].is_used());
+    }
+
+    #[test]
+    fn primary_partitions() {
+        let mut disk = MemDisk::new(BS, 100);
+        write_table(
+            &disk,
+            0,
+            &[(0x83, 1, 10), (0, 0, 0), (0x0c, 20, 30), (0x83, 90, 20)],
+        );
+        let partitions = parse_disk(&mut disk);
+        // The last one is out of the disk.
+        let spans: Vec<_> = partitions.iter().map(span).collect();
+        assert_eq!(spans, [(1, 1, 10), (3, 20, 30)]);
+        assert!(matches!(
+            partitions[0].kind,
+            PartitionKind::Mbr {
+                system_id: 0x83,
+                bootable: true
+            }
+        ));
+    }
+
+    #[test]
+    fn overflowing_entry() {
+        let entry = MbrEntry {
+            bootable: false,
+            system_id: 0x83,
+            start: 1,
+            count: 10,
+        };
+        assert!(entry.partition(5, u64::MAX, u64::MAX).is_none());
+        assert!(entry.partition(5, u64::MAX - 5, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn ebr_chain() {
+        let mut disk = MemDisk::new(BS, 400);
+        write_table(&disk, 0, &[(0x83, 1, 50), (0x05, 100, 200)]);
+        // Logical partitions start relative to their EBR, the next EBR
+        // relative to the extended partition.
+        write_table(&disk, 100, &[(0x83, 1, 10), (0x05, 20, 20)]);
+        write_table(&disk, 120, &[(0x83, 2, 5), (0x05, 40, 20)]);
+        write_table(&disk, 140, &[(0x83, 1, 7)]);
+        let spans: Vec<_> = parse_disk(&mut disk).iter().map(span).collect();
+        assert_eq!(spans, [(1, 1, 50), (5, 101, 10), (6, 122, 5), (7, 141, 7)]);
+    }
+
+    #[test]
+    fn ebr_chain_without_signature() {
+        let mut disk = MemDisk::new(BS, 400);
+        write_table(&disk, 0, &[(0x0f, 100, 200)]);
+        write_table(&disk, 100, &[(0x83, 1, 10), (0x05, 20, 20)]);
+        let spans: Vec<_> = parse_disk(&mut disk).iter().map(span).collect();
+        assert_eq!(spans, [(5, 101, 10)]);
+    }
+
+    #[test]
+    fn ebr_chain_loop() {
+        let mut disk = MemDisk::new(BS, 400);
+        write_table(&disk, 0, &[(0x05, 100, 200)]);
+        write_table(&disk, 100, &[(0x83, 1, 10), (0x05, 0, 200)]);
+        let partitions = parse_disk(&mut disk);
+        assert_eq!(partitions.len(), MAX_LOGICAL as usize);
+    }
+}
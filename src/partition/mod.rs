@@ -0,0 +1,448 @@
+//! Partitions of block devices, registered as block devices of their own.
+//!
+//! After probing, the partition table of each block device is read, and each
+//! partition is registered in [`AllDevices::block`] with the name of its disk
+//! followed by its number, e.g. `vda1`, or `ram0p1` if the disk name ends
+//! with a digit. MBR tables (with logical partitions in extended ones) and
+//! GPT tables (with the CRC checks and the backup header) are supported.
+//!
+//! A partition device only accesses its own blocks. The disk stays
+//! registered under its name and shares the driver with its partitions, so
+//! they are unbound together. A disk can't be unbound while some of its
+//! partitions are taken out of [`AllDevices::block`], as they still use its
+//! driver.
+//!
+//! The partition devices are of another type than the disks, so the
+//! `partition` feature enables `block-dyn`.
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use arrayvec::ArrayString;
+use spin::Mutex;
+
+use crate::{
+    block::check_request, prelude::*, AllDevices, AxDeviceInfo, DeviceLocation, DeviceName,
+};
+
+mod gpt;
+mod mbr;
+
+/// Name of the driver of partition devices, in their [`AxDeviceInfo`].
+const PARTITION_DRIVER: &str = "partition";
+
+/// Size of the sectors that partition tables are laid out in, at least.
+const SECTOR_SIZE: usize = 512;
+
+/// A GUID, as stored on disk in mixed-endian form.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Returns whether all bytes are zero, which marks an unused GPT entry.
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+/// Formats the GUID as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The table entry a partition comes from.
+#[derive(Debug, Clone, Copy)]
+pub enum PartitionKind {
+    /// An entry of an MBR or of an extended partition.
+    Mbr {
+        /// The partition type, e.g. `0x83` for Linux.
+        system_id: u8,
+        bootable: bool,
+    },
+    /// An entry of a GPT.
+    Gpt {
+        type_guid: Guid,
+        /// The GUID of the partition itself.
+        unique_guid: Guid,
+        attributes: u64,
+    },
+}
+
+/// A partition found on a block device.
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    /// Name of the partition device, e.g. `vda1`.
+    pub name: DeviceName,
+    /// Name of the disk, e.g. `vda`.
+    pub disk: DeviceName,
+    /// Number of the partition on the disk, starting from 1. Logical
+    /// partitions of an MBR start from 5.
+    pub number: u32,
+    /// First block of the partition on the disk.
+    pub start_block: u64,
+    pub num_blocks: u64,
+    pub kind: PartitionKind,
+    /// The name of a GPT partition, empty for MBR partitions.
+    pub label: ArrayString<108>,
+}
+
+impl PartitionInfo {
+    /// Returns the unique GUID of a GPT partition.
+    pub fn guid(&self) -> Option<Guid> {
+        match self.kind {
+            PartitionKind::Gpt { unique_guid, .. } => Some(unique_guid),
+            PartitionKind::Mbr { .. } => None,
+        }
+    }
+}
+
+type SharedDisk = Arc<Mutex<AxBlockDevice>>;
+
+/// A disk with partitions, shared with the partition devices.
+struct Disk {
+    dev: SharedDisk,
+    /// The name of the device given by its driver.
+    device_name: DeviceName,
+}
+
+impl BaseDriverOps for Disk {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+impl BlockDriverOps for Disk {
+    fn num_blocks(&self) -> u64 {
+        self.dev.lock().num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.dev.lock().block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.dev.lock().read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.dev.lock().write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.dev.lock().flush()
+    }
+}
+
+impl BlockRequestOps for Disk {
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.dev.lock().read_blocks(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.dev.lock().write_blocks(block_id, buf)
+    }
+
+    fn read_blocks_sg(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        self.dev.lock().read_blocks_sg(block_id, bufs)
+    }
+
+    fn write_blocks_sg(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        self.dev.lock().write_blocks_sg(block_id, bufs)
+    }
+}
+
+/// A partition device, which accesses `[start, start + num_blocks)` of its
+/// disk.
+struct Partition {
+    disk: SharedDisk,
+    /// The name of the partition device, e.g. `vda1`.
+    name: DeviceName,
+    start: u64,
+    num_blocks: u64,
+    block_size: usize,
+}
+
+impl Partition {
+    /// Returns the block on the disk of an access of `len` bytes at
+    /// `block_id`, if it's inside the partition.
+    fn disk_block(&self, block_id: u64, len: usize) -> DevResult<u64> {
+        check_request(self, block_id, len)?;
+        Ok(self.start + block_id)
+    }
+}
+
+impl BaseDriverOps for Partition {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl BlockDriverOps for Partition {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_id = self.disk_block(block_id, buf.len())?;
+        self.disk.lock().read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_id = self.disk_block(block_id, buf.len())?;
+        self.disk.lock().write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.disk.lock().flush()
+    }
+}
+
+impl BlockRequestOps for Partition {
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        check_request(self, block_id, buf.len())?;
+        self.disk.lock().read_blocks(self.start + block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        check_request(self, block_id, buf.len())?;
+        self.disk.lock().write_blocks(self.start + block_id, buf)
+    }
+
+    fn read_blocks_sg(&mut self, block_id: u64, bufs: &mut [&mut [u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        check_request(self, block_id, len)?;
+        self.disk.lock().read_blocks_sg(self.start + block_id, bufs)
+    }
+
+    fn write_blocks_sg(&mut self, block_id: u64, bufs: &[&[u8]]) -> DevResult {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        check_request(self, block_id, len)?;
+        self.disk
+            .lock()
+            .write_blocks_sg(self.start + block_id, bufs)
+    }
+}
+
+/// Reads the block at `block_id` of `disk` into a new buffer.
+fn read_block(disk: &mut dyn BlockRequestOps, block_id: u64) -> DevResult<Vec<u8>> {
+    let mut buf = vec![0; disk.block_size()];
+    disk.read_block(block_id, &mut buf)?;
+    Ok(buf)
+}
+
+/// Reads the partition table of `disk`.
+fn read_table(disk: &mut dyn BlockRequestOps) -> DevResult<Vec<PartitionInfo>> {
+    let block_size = disk.block_size();
+    if block_size < SECTOR_SIZE || block_size % SECTOR_SIZE != 0 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_block(disk, 0)?;
+    match mbr::parse_mbr(&mbr) {
+        Some(entries) if entries.iter().any(|e| e.system_id == mbr::GPT_PROTECTIVE) => {
+            gpt::parse(disk)
+        }
+        Some(entries) => mbr::parse(disk, &entries),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns the name of partition `number` of `disk`.
+fn partition_name(disk: &DeviceName, number: u32) -> DeviceName {
+    let mut name = DeviceName::new(disk);
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        name.push(b"p");
+    }
+    let mut digits = ArrayString::<10>::new();
+    let _ = fmt::write(&mut digits, format_args!("{}", number));
+    name.push(digits.as_bytes());
+    name
+}
+
+impl AllDevices {
+    /// Reads the partition tables of all block devices, and registers their
+    /// partitions.
+    pub(crate) fn scan_partitions(&mut self) {
+        let disks: Vec<DeviceName> = self
+            .block
+            .iter_with_info()
+            .filter(|(_, info)| info.driver != PARTITION_DRIVER)
+            .map(|(_, info)| info.name)
+            .collect();
+        for disk in disks {
+            self.scan_disk(&disk);
+        }
+    }
+
+    fn scan_disk(&mut self, name: &DeviceName) {
+        let Some(index) = self.block.position_by_name(name) else {
+            return;
+        };
+        let Some(dev) = self.block.get_mut(index) else {
+            return;
+        };
+        let mut partitions = match read_table(dev.as_mut()) {
+            Ok(partitions) if !partitions.is_empty() => partitions,
+            Ok(_) => return,
+            Err(e) => {
+                warn!("failed to read the partition table of {}: {:?}", name, e);
+                return;
+            }
+        };
+        let block_size = dev.block_size();
+
+        // Share the driver between the disk and its partitions.
+        let (dev, info) = self.block.take(index).unwrap();
+        let device_name = DeviceName::new(dev.device_name());
+        let dev = Arc::new(Mutex::new(dev));
+        self.block.insert(
+            index,
+            Box::new(Disk {
+                dev: dev.clone(),
+                device_name,
+            }),
+            info,
+        );
+        for partition in &mut partitions {
+            partition.name = partition_name(name, partition.number);
+            partition.disk = *name;
+            info!(
+                "partition {}: blocks [{}, {}) of {} {:?}",
+                partition.name,
+                partition.start_block,
+                partition.start_block + partition.num_blocks,
+                name,
+                partition.label
+            );
+            let info = AxDeviceInfo {
+                name: partition.name,
+                driver: PARTITION_DRIVER,
+                location: DeviceLocation::Platform,
+                irq: None,
+                probe_order: self.num_probed,
+            };
+            self.num_probed += 1;
+            self.block.push(
+                Box::new(Partition {
+                    disk: dev.clone(),
+                    name: partition.name,
+                    start: partition.start_block,
+                    num_blocks: partition.num_blocks,
+                    block_size,
+                }),
+                info,
+            );
+        }
+        self.partitions.extend(partitions);
+    }
+
+    /// Removes the partition device named `name`, returns its information.
+    pub(crate) fn unbind_partition(&mut self, name: &str) -> Option<AxDeviceInfo> {
+        let index = self.partitions.iter().position(|p| p.name == *name)?;
+        let (_, info) = self.block.take_by_name(name)?;
+        self.partitions.remove(index);
+        info!("unbinding partition {}", name);
+        Some(info)
+    }
+
+    /// Returns a partition of the disk named `disk` that is taken out of
+    /// [`AllDevices::block`], and so still uses the disk.
+    pub(crate) fn taken_partition_of(&self, disk: &str) -> Option<DeviceName> {
+        self.partitions
+            .iter()
+            .filter(|p| p.disk == *disk)
+            .find(|p| self.block.position_by_name(&p.name).is_none())
+            .map(|p| p.name)
+    }
+
+    /// Removes the partition devices of the disk named `disk`.
+    pub(crate) fn unbind_partitions_of(&mut self, disk: &str) {
+        let names: Vec<DeviceName> = self
+            .partitions
+            .iter()
+            .filter(|p| p.disk == *disk)
+            .map(|p| p.name)
+            .collect();
+        for name in names {
+            self.unbind_partition(&name);
+        }
+    }
+
+    /// Returns all partitions found on block devices.
+    pub fn partitions(&self) -> &[PartitionInfo] {
+        &self.partitions
+    }
+
+    /// Returns the partition named `name`, e.g. `vda1`.
+    pub fn partition_by_name(&self, name: &str) -> Option<&PartitionInfo> {
+        self.partitions.iter().find(|p| p.name == *name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::MemDisk;
+
+    #[test]
+    fn partition_names() {
+        let name = |disk: &str, number| partition_name(&DeviceName::new(disk), number);
+        assert_eq!(name("vda", 1).as_str(), "vda1");
+        assert_eq!(name("sdb", 12).as_str(), "sdb12");
+        assert_eq!(name("ram0", 1).as_str(), "ram0p1");
+        assert_eq!(name("mmcblk1", 5).as_str(), "mmcblk1p5");
+    }
+
+    #[test]
+    fn guid_display() {
+        let guid = Guid([
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ]);
+        let mut s = ArrayString::<36>::new();
+        fmt::write(&mut s, format_args!("{}", guid)).unwrap();
+        assert_eq!(s.as_str(), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    }
+
+    #[test]
+    fn no_table() {
+        let mut disk = MemDisk::new(SECTOR_SIZE, 8);
+        assert!(read_table(&mut disk).unwrap().is_empty());
+        // Partition tables are not looked for with blocks smaller than a
+        // sector.
+        let mut disk = MemDisk::new(SECTOR_SIZE / 2, 8);
+        assert!(read_table(&mut disk).unwrap().is_empty());
+        assert!(disk.take_requests().is_empty());
+    }
+}
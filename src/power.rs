@@ -0,0 +1,158 @@
+//! Suspend, resume and shutdown of all devices.
+//!
+//! Devices are suspended and shut down in the reverse order of probing, so
+//! that a device goes down before the devices it may depend on, and resumed
+//! in probe order.
+//!
+//! Devices taken out of [`AllDevices`] are still handled at the bus level:
+//! every device bound to a driver is quiesced, whoever owns it. Devices not
+//! on a bus, such as RAM disks, are handled by their driver, see
+//! [`DriverProbe::quiesce_global`](crate::DriverProbe::quiesce_global).
+//! Block devices taken out are flushed through their block cache if they
+//! have one, otherwise their owner must flush them before.
+
+use crate::{prelude::*, AllDevices, DeviceLocation, DriverEntry};
+
+cfg_if::cfg_if! {
+    if #[cfg(any(net_dyn, block_dyn, display_dyn))] {
+        type Storage = alloc::vec::Vec<BoundDevice>;
+    } else {
+        /// Maximum number of devices bound to drivers, if the heap is not used.
+        const MAX_BOUND: usize = 64;
+
+        type Storage = arrayvec::ArrayVec<BoundDevice, MAX_BOUND>;
+    }
+}
+
+/// What to do with a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerOp {
+    Suspend,
+    Resume,
+    Shutdown,
+}
+
+/// A device bound to a driver.
+struct BoundDevice {
+    /// The [probe order](crate::AxDeviceInfo::probe_order) of the device.
+    order: usize,
+    location: DeviceLocation,
+    driver: &'static DriverEntry,
+}
+
+/// The devices bound to drivers, in probe order.
+#[derive(Default)]
+pub(crate) struct ProbeOrder(Storage);
+
+impl ProbeOrder {
+    /// Records the device registered with probe order `order`.
+    pub(crate) fn push(
+        &mut self,
+        order: usize,
+        location: DeviceLocation,
+        driver: &'static DriverEntry,
+    ) {
+        #[cfg(not(any(net_dyn, block_dyn, display_dyn)))]
+        if self.0.is_full() {
+            warn!(
+                "too many bound devices (max {}), {} is not suspended",
+                MAX_BOUND, location
+            );
+            return;
+        }
+        self.0.push(BoundDevice {
+            order,
+            location,
+            driver,
+        });
+    }
+
+    /// Forgets the device with probe order `order`, after it's unbound.
+    pub(crate) fn remove(&mut self, order: usize) {
+        self.0.retain(|dev| dev.order != order);
+    }
+}
+
+impl AllDevices {
+    /// Shuts down all devices, before a reboot or before jumping into another
+    /// kernel.
+    ///
+    /// Block devices are flushed, virtio devices are reset, and DMA and
+    /// interrupts of PCI functions are disabled. The devices must not be used
+    /// afterwards.
+    pub fn shutdown(&mut self) {
+        info!("Shutting down device drivers...");
+        if let Err(e) = self.flush_all() {
+            warn!("failed to flush block devices: {:?}", e);
+        }
+        self.bus_op(PowerOp::Shutdown);
+        #[cfg(feature = "dma-debug")]
+        crate::dma::debug::report(None);
+    }
+
+    /// Suspends all devices: block devices are flushed, and DMA of PCI
+    /// functions is stopped.
+    ///
+    /// If a block device fails to flush, nothing is suspended and the error
+    /// is returned. Devices that can't flush, because they cache nothing,
+    /// don't fail.
+    pub fn suspend(&mut self) -> DevResult {
+        self.flush_all()?;
+        self.bus_op(PowerOp::Suspend);
+        Ok(())
+    }
+
+    /// Resumes all devices suspended by [`suspend`](Self::suspend).
+    pub fn resume(&mut self) {
+        self.bus_op(PowerOp::Resume);
+    }
+
+    /// Flushes all block devices in reverse probe order, then the block
+    /// caches of the devices taken out of `self`.
+    fn flush_all(&mut self) -> DevResult {
+        #[cfg(feature = "block")]
+        for order in (0..self.num_probed).rev() {
+            if let Some(i) = self.block.position_by_order(order) {
+                if let Some(dev) = self.block.get_mut(i) {
+                    match dev.flush() {
+                        // Nothing is cached by the device.
+                        Ok(()) | Err(DevError::Unsupported) => {}
+                        Err(e) => {
+                            warn!("failed to flush device #{}: {:?}", order, e);
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "block-cache")]
+        self.flush_block_caches()?;
+        Ok(())
+    }
+
+    /// Applies the bus level part of `op` to all devices bound to a driver,
+    /// whether or not they are still in `self`, in probe order for resume and
+    /// in reverse otherwise.
+    ///
+    /// Devices not on a bus are handled by their driver instead.
+    fn bus_op(&mut self, op: PowerOp) {
+        let count = self.probed.0.len();
+        for i in 0..count {
+            let i = if op == PowerOp::Resume {
+                i
+            } else {
+                count - 1 - i
+            };
+            let BoundDevice {
+                location, driver, ..
+            } = self.probed.0[i];
+            match (op, location) {
+                (PowerOp::Resume, DeviceLocation::Platform) => (driver.resume_global)(),
+                (_, DeviceLocation::Platform) => (driver.quiesce_global)(),
+                (PowerOp::Suspend, _) => self.suspend_bus_device(&location),
+                (PowerOp::Resume, _) => self.resume_bus_device(&location),
+                (PowerOp::Shutdown, _) => self.shutdown_bus_device(&location),
+            }
+        }
+    }
+}
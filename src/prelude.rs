@@ -3,7 +3,9 @@
 pub use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
 
 #[cfg(feature = "block")]
-pub use {crate::structs::AxBlockDevice, driver_block::BlockDriverOps};
+pub use {
+    crate::block::BlockRequestOps, crate::structs::AxBlockDevice, driver_block::BlockDriverOps,
+};
 #[cfg(feature = "display")]
 pub use {crate::structs::AxDisplayDevice, driver_display::DisplayDriverOps};
 #[cfg(feature = "net")]
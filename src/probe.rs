@@ -0,0 +1,356 @@
+//! Probing drivers, with deferral and dependencies between drivers.
+//!
+//! A driver is probed only after all of its [`Dependency`]s are met. A driver
+//! can also return [`ProbeResult::Defer`] if something it needs is not ready.
+//! In both cases the device is recorded with all the drivers that match it,
+//! and they are probed again after other drivers have registered new devices,
+//! until no more progress can be made.
+
+use arrayvec::ArrayVec;
+
+use crate::{prelude::*, AllDevices, AxDeviceEnum, AxDeviceInfo, DriverEntry, ScanStatus};
+
+#[cfg(bus = "pci")]
+use crate::PciIrq;
+#[cfg(bus = "pci")]
+use driver_pci::{DeviceFunction, DeviceFunctionInfo};
+
+/// Maximum number of probes that can be deferred at the same time.
+const MAX_DEFERRED: usize = 32;
+/// Maximum number of drivers tried for one device.
+const MAX_CANDIDATES: usize = 8;
+
+/// The drivers that match a device, in probe order.
+type Candidates = ArrayVec<&'static DriverEntry, MAX_CANDIDATES>;
+
+/// The result of probing a driver.
+pub enum ProbeResult {
+    /// The driver has found and initialized a device.
+    Device(AxDeviceEnum),
+    /// The driver does not handle the device, or found nothing.
+    NotFound,
+    /// The driver handles the device, but something it needs is not ready
+    /// yet. The probe is retried later, like `EPROBE_DEFER` in Linux.
+    Defer,
+    /// The driver handles the device, but failed to initialize it. Other
+    /// matching drivers are still tried, and the error is recorded in the
+    /// [inventory](AllDevices::inventory).
+    Error(DevError),
+}
+
+impl From<Option<AxDeviceEnum>> for ProbeResult {
+    fn from(dev: Option<AxDeviceEnum>) -> Self {
+        match dev {
+            Some(dev) => Self::Device(dev),
+            None => Self::NotFound,
+        }
+    }
+}
+
+/// Something a driver needs before it can be probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dependency {
+    /// The named driver has registered at least one device.
+    Driver(&'static str),
+    /// At least one device of the category has been registered, e.g. a
+    /// display for a framebuffer console.
+    Device(DeviceType),
+}
+
+/// Where a driver is probed.
+pub(crate) enum ProbeSite {
+    /// [`DriverProbe::probe_global`](crate::DriverProbe::probe_global).
+    Global,
+    /// A memory-mapped device.
+    #[cfg(bus = "mmio")]
+    Mmio { base: usize, size: usize },
+    /// A PCI function, with the interrupt set up for it.
+    #[cfg(bus = "pci")]
+    Pci {
+        bdf: DeviceFunction,
+        dev_info: DeviceFunctionInfo,
+        irq: PciIrq,
+    },
+}
+
+impl ProbeSite {
+    fn probe(&self, driver: &DriverEntry, info: &AxDeviceInfo) -> ProbeResult {
+        let res = match self {
+            Self::Global => (driver.probe_global)(),
+            #[cfg(bus = "mmio")]
+            Self::Mmio { base, size } => (driver.probe_mmio)(*base, *size),
+            #[cfg(bus = "pci")]
+            Self::Pci { bdf, dev_info, irq } => {
+                (driver.probe_pci)(&mut crate::bus::pci_root(), *bdf, dev_info, *irq)
+            }
+        };
+        // Give back the DMA handle the driver may have bound.
+        #[cfg(feature = "dma")]
+        if !matches!(res, ProbeResult::Device(_)) {
+            crate::dma::release_device(driver.name, &info.location);
+        }
+        let _ = info;
+        res
+    }
+}
+
+/// What happened to a device after probing all candidate drivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProbeOutcome {
+    /// A driver has registered it.
+    Bound,
+    /// A driver has deferred it.
+    Deferred,
+    /// No driver handles it.
+    NotFound,
+}
+
+/// The result of one pass over the drivers of a device.
+enum Probed {
+    Bound,
+    /// Deferred to the driver that returned [`ProbeResult::Defer`], or else
+    /// to the first driver whose dependencies are not met.
+    Deferred(&'static DriverEntry),
+    NotFound,
+}
+
+/// A device waiting to be probed again.
+struct DeferredProbe {
+    drivers: Candidates,
+    site: ProbeSite,
+    info: AxDeviceInfo,
+}
+
+/// Probes waiting to be retried.
+#[derive(Default)]
+pub(crate) struct DeferredProbes(ArrayVec<DeferredProbe, MAX_DEFERRED>);
+
+impl DeferredProbes {
+    /// Records a device to be probed again, fails if too many probes are
+    /// deferred.
+    fn push(&mut self, drivers: Candidates, site: ProbeSite, info: AxDeviceInfo) -> DevResult {
+        let probe = DeferredProbe {
+            drivers,
+            site,
+            info,
+        };
+        self.0.try_push(probe).map_err(|_| DevError::NoMemory)
+    }
+}
+
+impl AllDevices {
+    /// Returns whether all dependencies of `driver` are met.
+    fn dependencies_met(&self, driver: &DriverEntry) -> bool {
+        driver.depends_on.iter().all(|dep| match *dep {
+            Dependency::Driver(name) => self.has_device(|info| info.driver == name),
+            Dependency::Device(ty) => self.has_device_type(ty),
+        })
+    }
+
+    fn has_device(&self, f: impl Fn(&AxDeviceInfo) -> bool) -> bool {
+        #[cfg(feature = "net")]
+        if self.net.iter_with_info().any(|(_, info)| f(info)) {
+            return true;
+        }
+        #[cfg(feature = "block")]
+        if self.block.iter_with_info().any(|(_, info)| f(info)) {
+            return true;
+        }
+        #[cfg(feature = "display")]
+        if self.display.iter_with_info().any(|(_, info)| f(info)) {
+            return true;
+        }
+        let _ = f;
+        false
+    }
+
+    fn has_device_type(&self, ty: DeviceType) -> bool {
+        match ty {
+            #[cfg(feature = "net")]
+            DeviceType::Net => !self.net.is_empty(),
+            #[cfg(feature = "block")]
+            DeviceType::Block => !self.block.is_empty(),
+            #[cfg(feature = "display")]
+            DeviceType::Display => !self.display.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Probes `drivers` at `site` in order, until one of them registers a
+    /// device or defers it.
+    ///
+    /// Drivers whose dependencies are not met are skipped. If no other driver
+    /// handles the device, it's deferred, and all of `drivers` are probed
+    /// again later.
+    pub(crate) fn probe_site(
+        &mut self,
+        drivers: impl Iterator<Item = &'static DriverEntry>,
+        site: ProbeSite,
+        info: AxDeviceInfo,
+        deferred: &mut DeferredProbes,
+    ) -> ProbeOutcome {
+        let mut candidates = Candidates::new();
+        for driver in drivers {
+            if candidates.try_push(driver).is_err() {
+                warn!(
+                    "too many drivers match the device at {}, {:?} is not tried",
+                    info.location, driver.name
+                );
+            }
+        }
+        match self.probe_drivers(&candidates, &site, &info) {
+            Probed::Bound => ProbeOutcome::Bound,
+            Probed::Deferred(driver) => self.defer(driver, candidates, site, info, deferred),
+            Probed::NotFound => ProbeOutcome::NotFound,
+        }
+    }
+
+    /// Records the device at `site` to be probed again, deferred to `driver`.
+    ///
+    /// If too many probes are deferred, the device is treated as unclaimed,
+    /// so that the bus gives its resources back.
+    fn defer(
+        &mut self,
+        driver: &'static DriverEntry,
+        drivers: Candidates,
+        site: ProbeSite,
+        info: AxDeviceInfo,
+        deferred: &mut DeferredProbes,
+    ) -> ProbeOutcome {
+        let location = info.location;
+        match deferred.push(drivers, site, info) {
+            Ok(()) => {
+                self.inventory
+                    .set_status(&location, ScanStatus::Deferred(driver.name));
+                ProbeOutcome::Deferred
+            }
+            Err(e) => {
+                warn!(
+                    "too many deferred probes, giving up driver {:?} at {}",
+                    driver.name, location
+                );
+                self.inventory.set_error(&location, Some(driver.name), e);
+                ProbeOutcome::NotFound
+            }
+        }
+    }
+
+    /// Makes one pass over `drivers` at `site`, registering the device if one
+    /// of them handles it.
+    fn probe_drivers(
+        &mut self,
+        drivers: &[&'static DriverEntry],
+        site: &ProbeSite,
+        info: &AxDeviceInfo,
+    ) -> Probed {
+        let mut skipped = None;
+        for &driver in drivers {
+            if !self.dependencies_met(driver) {
+                skipped = skipped.or(Some(driver));
+                continue;
+            }
+            match site.probe(driver, info) {
+                ProbeResult::Device(dev) => {
+                    self.register_device(dev, driver, info.clone());
+                    return Probed::Bound;
+                }
+                ProbeResult::Defer => {
+                    debug!(
+                        "driver {:?} deferred the device at {}",
+                        driver.name, info.location
+                    );
+                    return Probed::Deferred(driver);
+                }
+                ProbeResult::Error(e) => self.probe_failed(driver, info, e),
+                ProbeResult::NotFound => {}
+            }
+        }
+        match skipped {
+            Some(driver) => {
+                debug!(
+                    "driver {:?} is waiting for {:?}",
+                    driver.name, driver.depends_on
+                );
+                Probed::Deferred(driver)
+            }
+            None => Probed::NotFound,
+        }
+    }
+
+    /// Probes the deferred devices again until no more devices are
+    /// registered. Those still deferred then are given up.
+    pub(crate) fn probe_deferred(&mut self, deferred: &mut DeferredProbes) {
+        let mut progress = true;
+        while progress && !deferred.0.is_empty() {
+            progress = false;
+            let mut i = 0;
+            while i < deferred.0.len() {
+                let probe = &deferred.0[i];
+                match self.probe_drivers(&probe.drivers, &probe.site, &probe.info) {
+                    Probed::Bound => {
+                        deferred.0.remove(i);
+                        progress = true;
+                    }
+                    Probed::Deferred(driver) => {
+                        self.inventory
+                            .set_status(&probe.info.location, ScanStatus::Deferred(driver.name));
+                        i += 1;
+                    }
+                    Probed::NotFound => {
+                        let probe = deferred.0.remove(i);
+                        self.inventory
+                            .set_status(&probe.info.location, ScanStatus::NoDriver);
+                        self.release_deferred(probe);
+                    }
+                }
+            }
+        }
+        for probe in deferred.0.drain(..) {
+            warn!(
+                "the device at {} is still deferred, giving up",
+                probe.info.location
+            );
+            self.release_deferred(probe);
+        }
+    }
+
+    /// Gives back the bus resources set up for a deferred probe that is not
+    /// retried anymore, like for a device that no driver claims.
+    fn release_deferred(&mut self, probe: DeferredProbe) {
+        #[cfg(bus = "pci")]
+        if let ProbeSite::Pci { bdf, irq, .. } = probe.site {
+            self.release_pci_function(bdf, irq);
+        }
+        let _ = probe;
+    }
+
+    fn register_device(
+        &mut self,
+        dev: AxDeviceEnum,
+        driver: &'static DriverEntry,
+        mut info: AxDeviceInfo,
+    ) {
+        info!(
+            "registered a new {:?} device at {} (irq {:?}): {:?}",
+            dev.device_type(),
+            info.location,
+            info.irq,
+            dev.device_name(),
+        );
+        info.driver = driver.name;
+        self.inventory
+            .set_status(&info.location, ScanStatus::Bound(driver.name));
+        let location = info.location;
+        self.add_device(dev, driver.naming, info);
+        self.probed.push(self.num_probed - 1, location, driver);
+    }
+
+    fn probe_failed(&mut self, driver: &DriverEntry, info: &AxDeviceInfo, e: DevError) {
+        warn!(
+            "driver {:?} failed to probe the device at {}: {:?}",
+            driver.name, info.location, e
+        );
+        self.inventory
+            .set_error(&info.location, Some(driver.name), e);
+    }
+}
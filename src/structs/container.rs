@@ -0,0 +1,207 @@
+use arrayvec::ArrayVec;
+
+use super::{AxDeviceInfo, DeviceLocation};
+
+/// Maximum number of devices of each category in the static device model.
+///
+/// It's set by the `AXDRIVER_MAX_DEVICES` environment variable at build time,
+/// and defaults to 4.
+pub const MAX_DEVICES: usize = parse_usize(env!("AXDRIVER_MAX_DEVICES"));
+
+/// Parses a build-time environment variable set by `build.rs`.
+pub(crate) const fn parse_usize(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    value
+}
+
+/// A structure that contains all device drivers of a category that uses the
+/// static device model.
+///
+/// The inner type is [`ArrayVec<D, N>`] and at most `N` devices (by default
+/// [`MAX_DEVICES`]) can be contained.
+pub struct AxDeviceContainer<D, const N: usize = MAX_DEVICES> {
+    devs: ArrayVec<D, N>,
+    infos: ArrayVec<AxDeviceInfo, N>,
+}
+
+/// A structure that contains all device drivers of a category that uses the
+/// dynamic device model.
+///
+/// The inner type is [`Vec<D>`], so there is no limit on the number of
+/// devices.
+///
+/// [`Vec<D>`]: alloc::vec::Vec
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+pub struct AxDynDeviceContainer<D> {
+    devs: alloc::vec::Vec<D>,
+    infos: alloc::vec::Vec<AxDeviceInfo>,
+}
+
+/// The container of the NIC devices, of the device model of the category.
+#[cfg(all(feature = "net", net_dyn))]
+pub type AxNetDevices = AxDynDeviceContainer<super::AxNetDevice>;
+/// The container of the NIC devices, of the device model of the category.
+#[cfg(all(feature = "net", not(net_dyn)))]
+pub type AxNetDevices = AxDeviceContainer<super::AxNetDevice>;
+/// The container of the block storage devices, of the device model of the
+/// category.
+#[cfg(all(feature = "block", block_dyn))]
+pub type AxBlockDevices = AxDynDeviceContainer<super::AxBlockDevice>;
+/// The container of the block storage devices, of the device model of the
+/// category.
+#[cfg(all(feature = "block", not(block_dyn)))]
+pub type AxBlockDevices = AxDeviceContainer<super::AxBlockDevice>;
+/// The container of the graphics display devices, of the device model of the
+/// category.
+#[cfg(all(feature = "display", display_dyn))]
+pub type AxDisplayDevices = AxDynDeviceContainer<super::AxDisplayDevice>;
+/// The container of the graphics display devices, of the device model of the
+/// category.
+#[cfg(all(feature = "display", not(display_dyn)))]
+pub type AxDisplayDevices = AxDeviceContainer<super::AxDisplayDevice>;
+
+/// Implements the methods shared by both containers.
+macro_rules! impl_container {
+    ([$($generics:tt)*] $container:ty, $storage:ty) => {
+        impl<$($generics)*> $container {
+            /// Returns number of devices in this container.
+            pub fn len(&self) -> usize {
+                self.devs.len()
+            }
+
+            /// Returns whether the container is empty.
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            /// Takes one device out of the container (will remove it from the container).
+            pub fn take_one(&mut self) -> Option<D> {
+                self.take_one_with_info().map(|(dev, _)| dev)
+            }
+
+            /// Same as [`take_one`](Self::take_one), but also returns the device
+            /// information.
+            pub fn take_one_with_info(&mut self) -> Option<(D, AxDeviceInfo)> {
+                self.take(0)
+            }
+
+            /// Returns the information of the device at `index`.
+            pub fn info(&self, index: usize) -> Option<&AxDeviceInfo> {
+                self.infos.get(index)
+            }
+
+            /// Returns an iterator over the devices and their information.
+            pub fn iter_with_info(&self) -> impl Iterator<Item = (&D, &AxDeviceInfo)> {
+                self.devs.iter().zip(self.infos.iter())
+            }
+
+            /// Returns the index of the device named `name`.
+            pub fn position_by_name(&self, name: &str) -> Option<usize> {
+                self.infos.iter().position(|info| info.name == *name)
+            }
+
+            /// Returns the index of the device at `location`.
+            pub fn position_by_location(&self, location: &DeviceLocation) -> Option<usize> {
+                self.infos
+                    .iter()
+                    .position(|info| info.location == *location)
+            }
+
+            /// Returns the index of the device registered `order`-th.
+            #[allow(dead_code)]
+            pub(crate) fn position_by_order(&self, order: usize) -> Option<usize> {
+                self.infos
+                    .iter()
+                    .position(|info| info.probe_order == order)
+            }
+
+            /// Returns the device named `name`.
+            pub fn get_by_name(&self, name: &str) -> Option<&D> {
+                self.devs.get(self.position_by_name(name)?)
+            }
+
+            /// Returns the device at `index` mutably.
+            #[allow(dead_code)]
+            pub(crate) fn get_mut(&mut self, index: usize) -> Option<&mut D> {
+                self.devs.get_mut(index)
+            }
+
+            /// Takes the device at `index` out of the container.
+            pub fn take(&mut self, index: usize) -> Option<(D, AxDeviceInfo)> {
+                if index < self.len() {
+                    Some((self.devs.remove(index), self.infos.remove(index)))
+                } else {
+                    None
+                }
+            }
+
+            /// Takes the device named `name` out of the container.
+            pub fn take_by_name(&mut self, name: &str) -> Option<(D, AxDeviceInfo)> {
+                self.take(self.position_by_name(name)?)
+            }
+
+            /// Constructs the container from one device.
+            pub fn from_one(dev: D) -> Self {
+                let mut container = Self::default();
+                container.push(dev, AxDeviceInfo::default());
+                container
+            }
+
+            /// Inserts one device into the container at `index`, which must
+            /// have room for it.
+            #[allow(dead_code)]
+            pub(crate) fn insert(&mut self, index: usize, dev: D, info: AxDeviceInfo) {
+                self.devs.insert(index, dev);
+                self.infos.insert(index, info);
+            }
+        }
+
+        impl<$($generics)*> core::ops::Deref for $container {
+            type Target = $storage;
+            fn deref(&self) -> &Self::Target {
+                &self.devs
+            }
+        }
+
+        impl<$($generics)*> Default for $container {
+            fn default() -> Self {
+                Self {
+                    devs: Default::default(),
+                    infos: Default::default(),
+                }
+            }
+        }
+    };
+}
+
+impl_container!([D, const N: usize] AxDeviceContainer<D, N>, ArrayVec<D, N>);
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+impl_container!([D] AxDynDeviceContainer<D>, alloc::vec::Vec<D>);
+
+impl<D, const N: usize> AxDeviceContainer<D, N> {
+    /// Adds one device into the container.
+    #[allow(dead_code)]
+    pub(crate) fn push(&mut self, dev: D, info: AxDeviceInfo) {
+        if self.devs.try_push(dev).is_ok() {
+            self.infos.push(info);
+        } else {
+            warn!("too many devices in one category (max {}), dropped", N);
+        }
+    }
+}
+
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+impl<D> AxDynDeviceContainer<D> {
+    /// Adds one device into the container.
+    #[allow(dead_code)]
+    pub(crate) fn push(&mut self, dev: D, info: AxDeviceInfo) {
+        self.devs.push(dev);
+        self.infos.push(info);
+    }
+}
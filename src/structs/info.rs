@@ -0,0 +1,166 @@
+use core::fmt;
+
+use driver_common::DeviceType;
+
+use crate::DeviceIrq;
+
+/// Maximum length of a device name.
+const MAX_NAME_LEN: usize = 16;
+
+/// Information about a registered device, besides the driver itself.
+#[derive(Debug, Clone, Default)]
+pub struct AxDeviceInfo {
+    /// The stable name of the device, e.g. `eth0` or `vda`.
+    pub name: DeviceName,
+    /// Name of the driver bound to the device, e.g. `virtio-blk`.
+    pub driver: &'static str,
+    /// Where the device is attached.
+    pub location: DeviceLocation,
+    /// The interrupt raised by the device, if it uses one.
+    pub irq: Option<DeviceIrq>,
+    /// The number of devices registered before this one.
+    pub probe_order: usize,
+}
+
+/// Where a device is attached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceLocation {
+    /// Not on a bus, e.g. a RAM disk or a device found by its driver.
+    #[default]
+    Platform,
+    /// A memory-mapped device at `[base, base + size)`.
+    Mmio { base: usize, size: usize },
+    /// A PCI function at `bus:device.function`.
+    Pci { bus: u8, device: u8, function: u8 },
+}
+
+impl fmt::Display for DeviceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Platform => write!(f, "platform"),
+            Self::Mmio { base, size } => write!(f, "[PA:{:#x}, PA:{:#x})", base, base + size),
+            Self::Pci {
+                bus,
+                device,
+                function,
+            } => write!(f, "{:02x}:{:02x}.{}", bus, device, function),
+        }
+    }
+}
+
+/// How devices probed by a driver are named.
+///
+/// The index counts devices of the same prefix in probe order, starting from
+/// 0 (or `a`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceNaming {
+    /// The prefix followed by a number, e.g. `eth0`, `eth1`.
+    Numbered(&'static str),
+    /// The prefix followed by letters, e.g. `vda`, ..., `vdz`, `vdaa`.
+    Lettered(&'static str),
+}
+
+impl DeviceNaming {
+    /// Returns the naming used by default for devices of category `ty`.
+    pub(crate) const fn default_for(ty: DeviceType) -> Self {
+        match ty {
+            DeviceType::Net => Self::Numbered("eth"),
+            DeviceType::Block => Self::Numbered("blk"),
+            DeviceType::Display => Self::Numbered("fb"),
+            _ => Self::Numbered("dev"),
+        }
+    }
+
+    /// Returns the name prefix.
+    pub const fn prefix(&self) -> &'static str {
+        match *self {
+            Self::Numbered(prefix) | Self::Lettered(prefix) => prefix,
+        }
+    }
+
+    /// Returns the name of the `index`-th device.
+    pub fn name(&self, index: usize) -> DeviceName {
+        let mut name = DeviceName::new(self.prefix());
+        match *self {
+            Self::Numbered(_) => {
+                let mut digits = [0u8; 20];
+                let (mut n, mut len) = (index, 0);
+                loop {
+                    digits[len] = b'0' + (n % 10) as u8;
+                    len += 1;
+                    n /= 10;
+                    if n == 0 {
+                        break;
+                    }
+                }
+                digits[..len].reverse();
+                name.push(&digits[..len]);
+            }
+            Self::Lettered(_) => {
+                let mut letters = [0u8; 16];
+                let (mut n, mut len) = (index + 1, 0);
+                while n > 0 && len < letters.len() {
+                    letters[len] = b'a' + ((n - 1) % 26) as u8;
+                    len += 1;
+                    n = (n - 1) / 26;
+                }
+                letters[..len].reverse();
+                name.push(&letters[..len]);
+            }
+        }
+        name
+    }
+}
+
+/// A device name stored inline, longer names are truncated.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceName {
+    buf: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl DeviceName {
+    /// Creates a name from a string.
+    pub fn new(name: &str) -> Self {
+        let mut this = Self::default();
+        this.push(name.as_bytes());
+        this
+    }
+
+    /// Returns the name as a string.
+    pub fn as_str(&self) -> &str {
+        // Names are expected to be ASCII, so truncation won't split a character.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(MAX_NAME_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+}
+
+impl core::ops::Deref for DeviceName {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for DeviceName {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl fmt::Display for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
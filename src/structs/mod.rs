@@ -1,10 +1,16 @@
-#[cfg_attr(feature = "dyn", path = "dyn.rs")]
-#[cfg_attr(not(feature = "dyn"), path = "static.rs")]
-mod imp;
+mod container;
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+mod r#dyn;
+mod info;
+mod r#static;
 
 use driver_common::{BaseDriverOps, DeviceType};
 
-pub use imp::*;
+pub use self::container::*;
+pub use self::info::*;
+#[cfg(any(net_dyn, block_dyn, display_dyn))]
+pub use self::r#dyn::*;
+pub use self::r#static::*;
 
 /// A unified enum that represents different categories of devices.
 #[allow(clippy::large_enum_variant)]
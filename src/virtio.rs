@@ -1,17 +1,19 @@
 use core::marker::PhantomData;
 use core::ptr::NonNull;
+use core::time::Duration;
 
-use axalloc::global_allocator;
-use axhal::mem::{phys_to_virt, virt_to_phys};
+use axhal::mem::phys_to_virt;
 use cfg_if::cfg_if;
-use driver_common::{BaseDriverOps, DevResult, DeviceType};
-use driver_virtio::{BufferDirection, PhysAddr, VirtIoHal};
+use driver_common::{BaseDriverOps, DevError, DevResult, DeviceType};
+use driver_virtio::{BufferDirection, PhysAddr, Transport, VirtIoHal};
 
-use crate::{drivers::DriverProbe, AxDeviceEnum};
+use crate::dma::{self, DmaDevice, DmaDirection, DmaMask, DmaRegion};
+use crate::{drivers::DriverProbe, AxDeviceEnum, DeviceLocation, DeviceNaming, ProbeResult};
 
 cfg_if! {
     if #[cfg(bus = "pci")] {
         use driver_pci::{PciRoot, DeviceFunction, DeviceFunctionInfo};
+        use crate::{PciDeviceId, PciIrq};
         type VirtIoTransport = driver_virtio::PciTransport;
     } else if #[cfg(bus =  "mmio")] {
         type VirtIoTransport = driver_virtio::MmioTransport;
@@ -20,12 +22,21 @@ cfg_if! {
 
 /// A trait for VirtIO device meta information.
 pub trait VirtIoDevMeta {
+    /// The name the driver is registered with.
+    const NAME: &'static str;
     const DEVICE_TYPE: DeviceType;
+    /// How the devices are named, see [`DriverProbe::NAMING`].
+    const NAMING: Option<DeviceNaming> = None;
+    /// IDs of the PCI functions of this device type, the transitional one
+    /// first, then the modern one.
+    #[cfg(bus = "pci")]
+    const PCI_IDS: &'static [PciDeviceId];
 
     type Device: BaseDriverOps;
     type Driver = VirtIoDriver<Self>;
 
-    fn try_new(transport: VirtIoTransport) -> DevResult<AxDeviceEnum>;
+    /// Creates the device, doing its DMA for `dma_dev`.
+    fn try_new(transport: VirtIoTransport, dma_dev: DmaDevice) -> DevResult<AxDeviceEnum>;
 }
 
 cfg_if! {
@@ -33,11 +44,23 @@ cfg_if! {
         pub struct VirtIoNet;
 
         impl VirtIoDevMeta for VirtIoNet {
+            const NAME: &'static str = "virtio-net";
             const DEVICE_TYPE: DeviceType = DeviceType::Net;
-            type Device = driver_virtio::VirtIoNetDev<VirtIoHalImpl, VirtIoTransport, 64>;
+            #[cfg(bus = "pci")]
+            const PCI_IDS: &'static [PciDeviceId] = &[PciDeviceId::new(0x1af4, 0x1000), PciDeviceId::new(0x1af4, 0x1041)];
+            type Device = driver_virtio::VirtIoNetDev<VirtIoHalImpl<{ DmaDevice::NET.index() }>, VirtIoTransport, 64>;
 
-            fn try_new(transport: VirtIoTransport) -> DevResult<AxDeviceEnum> {
-                Ok(AxDeviceEnum::from_net(Self::Device::try_new(transport)?))
+            fn try_new(transport: VirtIoTransport, dma_dev: DmaDevice) -> DevResult<AxDeviceEnum> {
+                #[cfg(net_dyn)]
+                return dma::with_dma_index!(dma_dev, DMA => {
+                    driver_virtio::VirtIoNetDev::<VirtIoHalImpl<DMA>, VirtIoTransport, 64>::try_new(transport)
+                        .map(AxDeviceEnum::from_net)
+                });
+                #[cfg(not(net_dyn))]
+                {
+                    debug_assert_eq!(dma_dev, DmaDevice::NET);
+                    Ok(AxDeviceEnum::from_net(Self::Device::try_new(transport)?))
+                }
             }
         }
     }
@@ -45,14 +68,48 @@ cfg_if! {
 
 cfg_if! {
     if #[cfg(block_dev = "virtio-blk")] {
+        use driver_block::BlockDriverOps;
+
+        use crate::block::{check_request, BlockRequestOps};
+
         pub struct VirtIoBlk;
 
         impl VirtIoDevMeta for VirtIoBlk {
+            const NAME: &'static str = "virtio-blk";
             const DEVICE_TYPE: DeviceType = DeviceType::Block;
-            type Device = driver_virtio::VirtIoBlkDev<VirtIoHalImpl, VirtIoTransport>;
+            #[cfg(bus = "pci")]
+            const PCI_IDS: &'static [PciDeviceId] = &[PciDeviceId::new(0x1af4, 0x1001), PciDeviceId::new(0x1af4, 0x1042)];
+            const NAMING: Option<DeviceNaming> = Some(DeviceNaming::Lettered("vd"));
+            type Device = driver_virtio::VirtIoBlkDev<VirtIoHalImpl<{ DmaDevice::BLOCK.index() }>, VirtIoTransport>;
 
-            fn try_new(transport: VirtIoTransport) -> DevResult<AxDeviceEnum> {
-                Ok(AxDeviceEnum::from_block(Self::Device::try_new(transport)?))
+            fn try_new(transport: VirtIoTransport, dma_dev: DmaDevice) -> DevResult<AxDeviceEnum> {
+                #[cfg(block_dyn)]
+                return dma::with_dma_index!(dma_dev, DMA => {
+                    driver_virtio::VirtIoBlkDev::<VirtIoHalImpl<DMA>, VirtIoTransport>::try_new(transport)
+                        .map(AxDeviceEnum::from_block_requests)
+                });
+                #[cfg(not(block_dyn))]
+                {
+                    debug_assert_eq!(dma_dev, DmaDevice::BLOCK);
+                    Ok(AxDeviceEnum::from_block_requests(Self::Device::try_new(transport)?))
+                }
+            }
+        }
+
+        /// A range of blocks is one virtio-blk request. A scatter-gather
+        /// list is one request per buffer, as the driver puts each request in
+        /// a single descriptor.
+        impl<const DMA: usize> BlockRequestOps
+            for driver_virtio::VirtIoBlkDev<VirtIoHalImpl<DMA>, VirtIoTransport>
+        {
+            fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                self.read_block(block_id, buf)
+            }
+
+            fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+                check_request(self, block_id, buf.len())?;
+                self.write_block(block_id, buf)
             }
         }
     }
@@ -63,11 +120,23 @@ cfg_if! {
         pub struct VirtIoGpu;
 
         impl VirtIoDevMeta for VirtIoGpu {
+            const NAME: &'static str = "virtio-gpu";
             const DEVICE_TYPE: DeviceType = DeviceType::Display;
-            type Device = driver_virtio::VirtIoGpuDev<VirtIoHalImpl, VirtIoTransport>;
+            #[cfg(bus = "pci")]
+            const PCI_IDS: &'static [PciDeviceId] = &[PciDeviceId::new(0x1af4, 0x1050)];
+            type Device = driver_virtio::VirtIoGpuDev<VirtIoHalImpl<{ DmaDevice::DISPLAY.index() }>, VirtIoTransport>;
 
-            fn try_new(transport: VirtIoTransport) -> DevResult<AxDeviceEnum> {
-                Ok(AxDeviceEnum::from_display(Self::Device::try_new(transport)?))
+            fn try_new(transport: VirtIoTransport, dma_dev: DmaDevice) -> DevResult<AxDeviceEnum> {
+                #[cfg(display_dyn)]
+                return dma::with_dma_index!(dma_dev, DMA => {
+                    driver_virtio::VirtIoGpuDev::<VirtIoHalImpl<DMA>, VirtIoTransport>::try_new(transport)
+                        .map(AxDeviceEnum::from_display)
+                });
+                #[cfg(not(display_dyn))]
+                {
+                    debug_assert_eq!(dma_dev, DmaDevice::DISPLAY);
+                    Ok(AxDeviceEnum::from_display(Self::Device::try_new(transport)?))
+                }
             }
         }
     }
@@ -76,83 +145,133 @@ cfg_if! {
 /// A common driver for all VirtIO devices that implements [`DriverProbe`].
 pub struct VirtIoDriver<D: VirtIoDevMeta + ?Sized>(PhantomData<D>);
 
+impl<D: VirtIoDevMeta> VirtIoDriver<D> {
+    /// Creates the device at `location` with a DMA handle bound to it.
+    fn probe_device(mut transport: VirtIoTransport, location: DeviceLocation) -> ProbeResult {
+        let dev = DmaDevice::bind_for(D::DEVICE_TYPE, D::NAME, location).and_then(|dma_dev| {
+            dma_dev.set_mask(dma_mask(&mut transport));
+            D::try_new(transport, dma_dev)
+        });
+        match dev {
+            Ok(dev) => ProbeResult::Device(dev),
+            Err(e) => ProbeResult::Error(e),
+        }
+    }
+}
+
 impl<D: VirtIoDevMeta> DriverProbe for VirtIoDriver<D> {
+    const NAMING: Option<DeviceNaming> = D::NAMING;
+
     #[cfg(bus = "mmio")]
-    fn probe_mmio(mmio_base: usize, mmio_size: usize) -> Option<AxDeviceEnum> {
+    const MMIO_COMPATIBLE: &'static [&'static str] = &["virtio,mmio"];
+
+    #[cfg(bus = "pci")]
+    const PCI_IDS: &'static [PciDeviceId] = D::PCI_IDS;
+
+    /// The queue and configuration change vectors are set after the device
+    /// is created, see [`probe_pci`](Self::probe_pci).
+    #[cfg(bus = "pci")]
+    const MSI: bool = true;
+
+    #[cfg(bus = "mmio")]
+    fn probe_mmio(mmio_base: usize, mmio_size: usize) -> ProbeResult {
         let base_vaddr = phys_to_virt(mmio_base.into());
         if let Some((ty, transport)) =
             driver_virtio::probe_mmio_device(base_vaddr.as_mut_ptr(), mmio_size)
         {
             if ty == D::DEVICE_TYPE {
-                match D::try_new(transport) {
-                    Ok(dev) => return Some(dev),
-                    Err(e) => {
-                        warn!(
-                            "failed to initialize MMIO device at [PA:{:#x}, PA:{:#x}): {:?}",
-                            mmio_base,
-                            mmio_base + mmio_size,
-                            e
-                        );
-                        return None;
-                    }
-                }
+                let location = DeviceLocation::Mmio {
+                    base: mmio_base,
+                    size: mmio_size,
+                };
+                return Self::probe_device(transport, location);
             }
         }
-        None
+        ProbeResult::NotFound
     }
 
+    /// With MSI-X, the vectors are set once virtio-drivers has set up the
+    /// queues, as it resets the device first, which forgets them.
     #[cfg(bus = "pci")]
     fn probe_pci(
         root: &mut PciRoot,
         bdf: DeviceFunction,
         dev_info: &DeviceFunctionInfo,
-    ) -> Option<AxDeviceEnum> {
-        if dev_info.vendor_id != 0x1af4 {
-            return None;
-        }
-        match (D::DEVICE_TYPE, dev_info.device_id) {
-            (DeviceType::Net, 0x1000) | (DeviceType::Net, 0x1040) => {}
-            (DeviceType::Block, 0x1001) | (DeviceType::Block, 0x1041) => {}
-            (DeviceType::Display, 0x1050) => {}
-            _ => return None,
+        irq: PciIrq,
+    ) -> ProbeResult {
+        let Some((ty, transport)) =
+            // The HAL is only used to map the BARs, which is not DMA.
+            driver_virtio::probe_pci_device::<VirtIoHalImpl<0>>(root, bdf, dev_info)
+        else {
+            return ProbeResult::NotFound;
+        };
+        if ty != D::DEVICE_TYPE {
+            return ProbeResult::NotFound;
         }
-
-        if let Some((ty, transport)) =
-            driver_virtio::probe_pci_device::<VirtIoHalImpl>(root, bdf, dev_info)
-        {
-            if ty == D::DEVICE_TYPE {
-                match D::try_new(transport) {
-                    Ok(dev) => return Some(dev),
-                    Err(e) => {
-                        warn!(
-                            "failed to initialize PCI device at {}({}): {:?}",
-                            bdf, dev_info, e
-                        );
-                        return None;
-                    }
-                }
+        let res = Self::probe_device(transport, crate::bus::pci_location(bdf));
+        if let (ProbeResult::Device(_), PciIrq::MsiX { count, .. }) = (&res, irq) {
+            let set = crate::bus::VirtIoCommonCfg::new(root, bdf)
+                .is_some_and(|common| common.set_msix_vectors(count));
+            if !set {
+                warn!("{}: failed to set the MSI-X vectors at {}", D::NAME, bdf);
             }
         }
-        None
+        res
+    }
+}
+
+/// The HAL of the virtio devices doing their DMA for the [`DmaDevice`] at
+/// index `DMA`.
+pub struct VirtIoHalImpl<const DMA: usize>;
+
+impl<const DMA: usize> VirtIoHalImpl<DMA> {
+    const DEV: DmaDevice = DmaDevice::from_index(DMA);
+}
+
+/// Returns the DMA addresses the device behind `transport` can reach.
+///
+/// Modern devices take 64-bit addresses. Legacy ones take the address of
+/// each queue as a 32-bit number of 4 KiB pages.
+fn dma_mask(transport: &mut VirtIoTransport) -> DmaMask {
+    const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+    if transport.read_device_features() & VIRTIO_F_VERSION_1 != 0 {
+        DmaMask::BITS_64
+    } else {
+        DmaMask::bits(32 + 12)
     }
 }
 
-pub struct VirtIoHalImpl;
+/// How long [`VirtIoHal::share`] waits for a bounce buffer to be freed.
+const SHARE_TIMEOUT: Duration = Duration::from_secs(1);
 
-unsafe impl VirtIoHal for VirtIoHalImpl {
+const fn dma_direction(direction: BufferDirection) -> DmaDirection {
+    match direction {
+        BufferDirection::DriverToDevice => DmaDirection::ToDevice,
+        BufferDirection::DeviceToDriver => DmaDirection::FromDevice,
+        BufferDirection::Both => DmaDirection::Bidirectional,
+    }
+}
+
+unsafe impl<const DMA: usize> VirtIoHal for VirtIoHalImpl<DMA> {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
-        let vaddr = if let Ok(vaddr) = global_allocator().alloc_pages(pages, 0x1000) {
-            vaddr
-        } else {
-            return (0, NonNull::dangling());
-        };
-        let paddr = virt_to_phys(vaddr.into());
-        let ptr = NonNull::new(vaddr as _).unwrap();
-        (paddr.as_usize(), ptr)
+        match dma::alloc_coherent(Self::DEV, pages * dma::PAGE_SIZE, Self::DEV.mask()) {
+            Ok(region) => (region.dma_addr, region.vaddr),
+            Err(e) => {
+                error!("virtio: failed to allocate {} DMA pages: {:?}", pages, e);
+                (0, NonNull::dangling())
+            }
+        }
     }
 
-    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
-        global_allocator().dealloc_pages(vaddr.as_ptr() as usize, pages);
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        dma::free_coherent(
+            Self::DEV,
+            DmaRegion {
+                vaddr,
+                dma_addr: paddr,
+                size: pages * dma::PAGE_SIZE,
+            },
+        );
         0
     }
 
@@ -161,12 +280,39 @@ unsafe impl VirtIoHal for VirtIoHalImpl {
         NonNull::new(phys_to_virt(paddr.into()).as_mut_ptr()).unwrap()
     }
 
-    #[inline]
-    unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
-        let vaddr = buffer.as_ptr() as *mut u8 as usize;
-        virt_to_phys(vaddr.into()).into()
+    /// Waits for other buffers to be unshared if all bounce buffers are in
+    /// use, as virtio-drivers has no way to fail here. Panics if none is
+    /// freed within [`SHARE_TIMEOUT`].
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        let mut deadline = None;
+        loop {
+            match dma::map(
+                Self::DEV,
+                buffer,
+                dma_direction(direction),
+                Self::DEV.mask(),
+            ) {
+                Ok(dma_addr) => return dma_addr,
+                Err(DevError::Again) => {
+                    let now = axhal::time::current_time();
+                    match deadline {
+                        None => {
+                            warn!("virtio: all DMA bounce buffers are in use, waiting");
+                            deadline = Some(now + SHARE_TIMEOUT);
+                        }
+                        Some(deadline) if now >= deadline => {
+                            panic!("virtio: no DMA bounce buffer freed in {:?}", SHARE_TIMEOUT)
+                        }
+                        Some(_) => core::hint::spin_loop(),
+                    }
+                }
+                Err(e) => panic!("virtio: failed to map DMA buffer: {:?}", e),
+            }
+        }
     }
 
     #[inline]
-    unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {}
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        dma::unmap(Self::DEV, paddr, buffer, dma_direction(direction))
+    }
 }